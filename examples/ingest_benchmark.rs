@@ -0,0 +1,97 @@
+//! Sustained ingest benchmark.
+//!
+//! Many concurrent TCP clients send JSON lines as fast as they can, while the rate at which the
+//! server stores them is read from `/api/health`. Start a server (ideally a release build), then:
+//!
+//! ```bash
+//! cargo run --release --example ingest_benchmark -- [clients] [records per client]
+//! ```
+//!
+//! `IRONLOG_TCP` and `IRONLOG_API` point the benchmark at a server not on the default ports.
+
+use std::io::{BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let clients: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(64);
+    let records_per_client: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(20_000);
+    let tcp_addr = std::env::var("IRONLOG_TCP").unwrap_or_else(|_| "127.0.0.1:5000".to_string());
+    let api_addr = std::env::var("IRONLOG_API").unwrap_or_else(|_| "127.0.0.1:8000".to_string());
+    let total = (clients * records_per_client) as u64;
+
+    let written_before = written(&api_addr);
+    println!("{} clients sending {} records each ({} total)", clients, records_per_client, total);
+
+    let start = Instant::now();
+    let senders: Vec<_> = (0..clients)
+        .map(|client| {
+            let tcp_addr = tcp_addr.clone();
+            thread::spawn(move || send_records(&tcp_addr, client, records_per_client))
+        })
+        .collect();
+
+    // Report the storage rate every second until everything sent has been written
+    let mut last = (Instant::now(), written_before);
+    let mut stalled_since = Instant::now();
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let now = (Instant::now(), written(&api_addr));
+        let stored = now.1 - written_before;
+        let rate = (now.1 - last.1) as f64 / (now.0 - last.0).as_secs_f64();
+        println!("{:>6.1}s  {:>10} stored  {:>10.0} records/s", start.elapsed().as_secs_f64(), stored, rate);
+
+        if stored >= total {
+            break;
+        }
+        if now.1 != last.1 {
+            stalled_since = Instant::now();
+        } else if stalled_since.elapsed() > Duration::from_secs(10) {
+            println!("No progress for 10s, giving up ({} records missing)", total - stored);
+            break;
+        }
+        last = now;
+    }
+    let elapsed = start.elapsed();
+
+    for sender in senders {
+        sender.join().expect("Client thread panicked");
+    }
+    println!(
+        "Stored {} records in {:.2}s: {:.0} records/s sustained",
+        written(&api_addr) - written_before,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn send_records(tcp_addr: &str, client: usize, records: usize) {
+    let stream = TcpStream::connect(tcp_addr).expect("Failed to connect to the log server");
+    let mut writer = BufWriter::with_capacity(64 * 1024, stream);
+    for i in 0..records {
+        writeln!(
+            writer,
+            r#"{{"level":"INFO","message":"benchmark record {} from client {}","target":"ingest_benchmark","module_path":null,"file":null,"line":null,"hash":"bench-{}","timestamp":"{}"}}"#,
+            i,
+            client,
+            client,
+            chrono::Utc::now().to_rfc3339()
+        )
+        .expect("Failed to send record");
+    }
+    writer.flush().expect("Failed to send record");
+}
+
+/// Rows written by the server so far, from `/api/health`.
+fn written(api_addr: &str) -> u64 {
+    let mut stream = TcpStream::connect(api_addr).expect("Failed to connect to the API");
+    write!(stream, "GET /api/health HTTP/1.0\r\nHost: {}\r\n\r\n", api_addr).expect("Failed to query health");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("Failed to read health");
+
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    let health: serde_json::Value = serde_json::from_str(body).expect("Unexpected health response");
+    health["written"].as_u64().expect("Health response has no `written` count")
+}
@@ -0,0 +1,45 @@
+-- Tables as they were before schema versioning
+
+CREATE TABLE IF NOT EXISTS logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    level TEXT,
+    severity INTEGER,
+    message TEXT,
+    target TEXT,
+    module_path TEXT,
+    file TEXT,
+    line INTEGER,
+    hash TEXT,
+    timestamp TEXT,
+    fields TEXT,
+    peer TEXT,
+    session_id TEXT
+);
+
+-- Logs of hashes evicted with --hash-eviction archive
+CREATE TABLE IF NOT EXISTS archived_logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    level TEXT,
+    severity INTEGER,
+    message TEXT,
+    target TEXT,
+    module_path TEXT,
+    file TEXT,
+    line INTEGER,
+    hash TEXT,
+    timestamp TEXT,
+    fields TEXT,
+    peer TEXT,
+    session_id TEXT,
+    archived_at TEXT
+);
+
+-- Frames that could not be decoded, kept for debugging broken clients
+CREATE TABLE IF NOT EXISTS rejected_lines (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    line TEXT,
+    error TEXT,
+    peer TEXT,
+    source TEXT,
+    timestamp TEXT
+);
@@ -0,0 +1,9 @@
+-- Log queries and the retention sweep look logs up by hash, ordered by time or insertion, and
+-- per-level retention by hash and level, ordered by time
+
+CREATE INDEX IF NOT EXISTS idx_logs_hash_timestamp ON logs (hash, timestamp);
+CREATE INDEX IF NOT EXISTS idx_logs_hash_id ON logs (hash, id);
+CREATE INDEX IF NOT EXISTS idx_logs_hash_level_timestamp ON logs (hash, level, timestamp);
+
+-- Log queries filtered by minimum severity or sorted by it
+CREATE INDEX IF NOT EXISTS idx_logs_hash_severity_timestamp ON logs (hash, severity, timestamp);
@@ -0,0 +1,217 @@
+// access.rs
+
+//! IP allow/deny lists and connection limits shared by the log listeners and the HTTP API.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`. A bare address is a /32 or /128.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address `{}`", address))?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in `{}`", cidr))?,
+            None => max_len,
+        };
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Whether `ip` may connect: it must not match `deny` and, when `allow` is not empty, must match `allow`.
+pub fn is_permitted(ip: IpAddr, allow: &[Cidr], deny: &[Cidr]) -> bool {
+    !deny.iter().any(|cidr| cidr.contains(ip)) && (allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip)))
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    TooManyConnections,
+    TooManyConnectionsFromPeer,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::TooManyConnections => write!(f, "connection limit reached"),
+            Refusal::TooManyConnectionsFromPeer => write!(f, "per-IP connection limit reached"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts open connections against a global and a per-IP limit.
+#[derive(Clone, Default)]
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        ConnectionLimiter {
+            max_connections,
+            max_connections_per_ip,
+            open: Arc::default(),
+        }
+    }
+
+    /// Number of connections currently open.
+    pub fn open_connections(&self) -> usize {
+        self.open.lock().unwrap().total
+    }
+
+    /// Count a new connection from `ip`. The returned guard releases it when dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionGuard, Refusal> {
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().unwrap();
+        if self.max_connections.is_some_and(|max| open.total >= max) {
+            return Err(Refusal::TooManyConnections);
+        }
+        let from_ip = open.by_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_connections_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(Refusal::TooManyConnectionsFromPeer);
+        }
+
+        open.total += 1;
+        open.by_ip.insert(ip, from_ip + 1);
+        Ok(ConnectionGuard {
+            ip,
+            open: Arc::clone(&self.open),
+        })
+    }
+}
+
+/// An open connection, counted until dropped.
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_networks_and_bare_addresses() {
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("192.168.1.7".parse::<Cidr>().unwrap().to_string(), "192.168.1.7/32");
+        assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn matches_addresses_within_the_prefix() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.255.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("fd00::1")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fdab::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_ipv6_as_ipv4() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(ip("::ffff:10.0.0.1")));
+        let mapped: Cidr = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(mapped.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let allow = cidrs(&["10.0.0.0/8"]);
+        let deny = cidrs(&["10.0.0.5"]);
+        assert!(is_permitted(ip("10.0.0.4"), &allow, &deny));
+        assert!(!is_permitted(ip("10.0.0.5"), &allow, &deny));
+        assert!(!is_permitted(ip("192.168.0.1"), &allow, &deny));
+    }
+
+    #[test]
+    fn empty_allow_list_permits_everyone_not_denied() {
+        let deny = cidrs(&["192.168.0.0/16"]);
+        assert!(is_permitted(ip("10.0.0.1"), &[], &deny));
+        assert!(!is_permitted(ip("192.168.3.4"), &[], &deny));
+        assert!(is_permitted(ip("::1"), &[], &[]));
+    }
+}
@@ -2,19 +2,33 @@
 extern crate rocket;
 
 use ironlog::config::Config;
-use ironlog::types::LogMessage;
-use ironlog::client_handler;
-
-use rocket::http::ContentType;
+use ironlog::types::{normalize_timestamp, LogMessage, RejectedLine};
+use ironlog::client_handler::{self, Dropped, Ingest, Peer, ShuttingDown};
+use ironlog::access::{self, Cidr};
+use ironlog::retention::{RetentionPolicy, StorageInfo};
+use ironlog::retention_rules::{ReplaceError, RetentionRule};
+use ironlog::sweep::SweepMetrics;
+use ironlog::writer::WriterHealth;
+use ironlog::{elasticsearch, gelf, loki, otlp, schema};
+
+use rocket::data::{Data, ToByteUnit};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
 use rocket::form::FromForm;
 use rocket::serde::json::Json;
 use include_dir::{include_dir, Dir};
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
-use sqlx::{SqlitePool, sqlite::SqlitePoolOptions, Row};
+use sqlx::{SqlitePool, sqlite::{SqliteAutoVacuum, SqliteConnectOptions, SqlitePoolOptions}, Row};
 use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use chrono::{Utc, Duration};
 use clap::Parser;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 
@@ -42,6 +56,49 @@ async fn optimize_sqlite(pool: &SqlitePool) {
         .expect("Failed to set cache size");
 }
 
+// Databases created before incremental auto-vacuum need rebuilding once for it to take effect
+async fn enable_incremental_vacuum(pool: &SqlitePool) {
+    let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum;")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to read auto_vacuum mode");
+
+    // 2 is INCREMENTAL
+    if auto_vacuum != 2 {
+        println!("Rebuilding the database to enable incremental vacuum, this may take a while");
+        // VACUUM needs room for a full copy, so a nearly full disk must not stop the server
+        if let Err(e) = sqlx::query("VACUUM;").execute(pool).await {
+            eprintln!(
+                "Failed to rebuild the database, continuing without incremental vacuum: {}. \
+                 Logs are still deleted to fit --max-db-size, but the file won't shrink.",
+                e
+            );
+        }
+    }
+}
+
+// Resolves on SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Fold the WAL back into the database file so nothing is left to replay on the next start
+async fn checkpoint_wal(pool: &SqlitePool) {
+    if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE);").execute(pool).await {
+        eprintln!("Failed to checkpoint the WAL: {}", e);
+    }
+}
+
 #[rocket::main]
 async fn main() {
     let config = Config::parse();
@@ -58,77 +115,133 @@ async fn main() {
 
     // Initialize the database connection pool
     let db_url = format!("sqlite://{}", db_path);
+    // Incremental auto-vacuum lets deleted space be handed back to the filesystem
+    let connect_options = SqliteConnectOptions::from_str(&db_url)
+        .expect("Failed to parse database URL")
+        .auto_vacuum(SqliteAutoVacuum::Incremental);
     let db_pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&db_url)
+        .connect_with(connect_options)
         .await
         .expect("Failed to create pool.");
+    if config.max_db_size.is_some() {
+        enable_incremental_vacuum(&db_pool).await;
+    }
 
-    // Ensure the logs table exists
-    sqlx::query("
-        CREATE TABLE IF NOT EXISTS logs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            level TEXT,
-            message TEXT,
-            target TEXT,
-            module_path TEXT,
-            file TEXT,
-            line INTEGER,
-            hash TEXT,
-            timestamp TEXT
-        )
-    ")
-    .execute(&db_pool)
-    .await
-    .expect("Failed to create logs table.");
+    // Create the tables, or bring an existing database up to date
+    schema::migrate(&db_pool, &config.level_aliases).await;
 
     // Optimize SQLite for performance
     optimize_sqlite(&db_pool).await;
 
-    // Start the log handler in a separate task
-    let db_pool_clone = db_pool.clone();
-    tokio::spawn(async move {
-        client_handler::start_log_handler(db_pool_clone, config_arc).await;
-    });
+    // Start the log handler
+    let (ingest, shutdown) = client_handler::start_log_handler(db_pool.clone(), config_arc).await;
 
     // Launch the Rocket server
     let api_server_ip = config.api_server_ip.parse::<std::net::IpAddr>().expect("Invalid IP address for API server");
     let figment = rocket::Config::figment()
         .merge(("address", api_server_ip))
-        .merge(("port", config.api_server_port));
+        .merge(("port", config.api_server_port))
+        // Signals are handled below, so Rocket only stops once the log pipeline is flushed
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
+
+    let access_control = AccessControl {
+        allow: config.allow.clone(),
+        deny: config.deny.clone(),
+    };
+
+    let shutdown_grace = std::time::Duration::from_secs(config.shutdown_grace);
+    let checkpoint_pool = db_pool.clone();
 
-    rocket::custom(figment)
+    let rocket = rocket::custom(figment)
+        .attach(access_control)
         .manage(db_pool)
         .manage(config) // Manage the original Config, not the Arc<Config>
+        .manage(ingest)
         .mount(
             "/api",
             routes![
                 get_hashes,
                 get_logs,
+                get_log_peers,
                 get_date_range,
                 get_log_info,
                 purge_logs,
                 insert_log,
+                get_rejected_lines,
+                get_health,
+                get_retention_rules,
+                put_retention_rules,
+                get_metrics,
             ],
         )
-        .mount("/", routes![index, serve_file])
-        .launch()
+        .mount("/", routes![index, serve_file, gelf_http, otlp_logs, loki_push, forbidden])
+        .mount("/es", routes![es_info, es_license, es_bulk, es_index_bulk])
+        .ignite()
         .await
-        .unwrap();
+        .expect("Failed to start the API server");
+
+    let rocket_shutdown = rocket.shutdown();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        println!("Shutting down, storing queued logs");
+        shutdown.run(shutdown_grace).await;
+        checkpoint_wal(&checkpoint_pool).await;
+        rocket_shutdown.notify();
+    });
+
+    rocket.launch().await.unwrap();
 }
 
-pub fn truncate_string(s: &str, max_bytes: usize) -> String {
-    if s.len() <= max_bytes {
-        s.to_string()
-    } else {
-        let mut end = max_bytes;
-        while !s.is_char_boundary(end) && end > 0 {
-            end -= 1;
+// The sender of an HTTP request, recorded with the logs it carries, and its address for
+// rejected lines
+struct RemotePeer(Peer, SocketAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RemotePeer {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote = request.remote().unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        Outcome::Success(RemotePeer(Peer::new(remote.ip()), remote))
+    }
+}
+
+const FORBIDDEN_PATH: &str = "/__ironlog/forbidden";
+
+// Sends API clients outside the `--allow`/`--deny` lists to `forbidden` before any route runs
+struct AccessControl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+#[rocket::async_trait]
+impl Fairing for AccessControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "IP allow/deny lists",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        // The socket address, not `client_ip()`, which trusts the X-Real-IP header
+        let permitted = request
+            .remote()
+            .is_some_and(|remote| access::is_permitted(remote.ip(), &self.allow, &self.deny));
+        if !permitted {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(FORBIDDEN_PATH).expect("Invalid forbidden path"));
         }
-        s[..end].to_string()
     }
 }
 
+#[get("/__ironlog/forbidden")]
+fn forbidden() -> Status {
+    Status::Forbidden
+}
+
 #[get("/")]
 fn index() -> Option<(ContentType, Vec<u8>)> {
     let file = STATIC_DIR.get_file("index.html")?;
@@ -189,6 +302,12 @@ struct LogQuery {
     count: Option<i64>,
     start: Option<String>,
     end: Option<String>,
+    /// Only return logs at least this severe
+    min_severity: Option<i64>,
+    /// `severity` to list the most severe logs first instead of the newest
+    sort: Option<String>,
+    /// Only return logs sent from this IP address
+    peer: Option<String>,
 }
 
 #[get("/logs/<hash>?<q..>")]
@@ -196,19 +315,23 @@ async fn get_logs(
     hash: &str,
     q: Option<LogQuery>,
     db_pool: &rocket::State<SqlitePool>,
-) -> Option<Json<Vec<LogMessage>>> {
+) -> Result<Json<Vec<LogMessage>>, (Status, String)> {
     use sqlx::QueryBuilder;
 
     let mut builder = QueryBuilder::<sqlx::Sqlite>::new("
         SELECT
             level,
+            severity,
             message,
             target,
             module_path,
             file,
             line,
             hash,
-            timestamp
+            timestamp,
+            fields,
+            peer,
+            session_id
         FROM logs
         WHERE hash = ");
     builder.push_bind(hash);
@@ -216,18 +339,34 @@ async fn get_logs(
     let mut count = None;
 
     if let Some(ref query_params) = q {
+        // Bounds are compared as text, so they must be in the stored form too
+        let bound = |name: &str, value: &str| {
+            normalize_timestamp(value).ok_or_else(|| (Status::BadRequest, format!("invalid {} timestamp {:?}", name, value)))
+        };
         if let Some(ref s) = query_params.start {
             builder.push(" AND timestamp >= ");
-            builder.push_bind(s);
+            builder.push_bind(bound("start", s)?);
         }
         if let Some(ref e) = query_params.end {
             builder.push(" AND timestamp <= ");
-            builder.push_bind(e);
+            builder.push_bind(bound("end", e)?);
+        }
+        if let Some(ref peer) = query_params.peer {
+            builder.push(" AND peer = ");
+            builder.push_bind(peer);
+        }
+        if let Some(min_severity) = query_params.min_severity {
+            builder.push(" AND severity >= ");
+            builder.push_bind(min_severity);
         }
         count = query_params.count;
     }
 
-    builder.push(" ORDER BY timestamp DESC");
+    if q.as_ref().and_then(|query_params| query_params.sort.as_deref()) == Some("severity") {
+        builder.push(" ORDER BY severity DESC, timestamp DESC");
+    } else {
+        builder.push(" ORDER BY timestamp DESC");
+    }
 
     if let Some(c) = count {
         builder.push(" LIMIT ");
@@ -239,9 +378,45 @@ async fn get_logs(
     let logs = query
         .fetch_all(db_pool.inner())
         .await
-        .ok()?;
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+
+    Ok(Json(logs))
+}
 
-    Some(Json(logs))
+#[derive(Serialize)]
+struct LogPeer {
+    peer: Option<String>,
+    count: i64,
+    sessions: i64,
+    last_seen: String,
+}
+
+// Every address that has sent logs under a hash; more than one usually means nodes share a hash
+#[get("/logs/<hash>/peers")]
+async fn get_log_peers(hash: &str, db_pool: &rocket::State<SqlitePool>) -> Json<Vec<LogPeer>> {
+    let rows = sqlx::query(
+        "SELECT peer, COUNT(*) AS count, COUNT(DISTINCT session_id) AS sessions, MAX(timestamp) AS last_seen
+        FROM logs
+        WHERE hash = ?
+        GROUP BY peer
+        ORDER BY last_seen DESC",
+    )
+    .bind(hash)
+    .fetch_all(db_pool.inner())
+    .await
+    .expect("Failed to fetch log peers.");
+
+    let peers = rows
+        .into_iter()
+        .map(|row| LogPeer {
+            peer: row.get("peer"),
+            count: row.get("count"),
+            sessions: row.get("sessions"),
+            last_seen: row.get("last_seen"),
+        })
+        .collect();
+
+    Json(peers)
 }
 
 
@@ -272,6 +447,8 @@ struct LogInfo {
     min_date: String,
     max_date: String,
     hash_list: Vec<String>,
+    retention_policy: RetentionPolicy,
+    storage: StorageInfo,
 }
 
 // Endpoint to get log info
@@ -279,6 +456,7 @@ struct LogInfo {
 async fn get_log_info(
     db_pool: &rocket::State<SqlitePool>,
     config: &rocket::State<Config>,
+    ingest: &rocket::State<Ingest>,
 ) -> Option<Json<LogInfo>> {
     // Get the size of the db file
     let db_path = &config.log_db;
@@ -291,6 +469,10 @@ async fn get_log_info(
         .await
         .ok()?;
 
+    let storage = StorageInfo::read(db_pool.inner(), config.max_db_size, ingest.health().ingest_rate, total_log_count)
+        .await
+        .ok()?;
+
     // Get number of hashes
     let number_of_hashes: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT hash) FROM logs")
         .fetch_one(db_pool.inner())
@@ -316,6 +498,8 @@ async fn get_log_info(
             min_date: (Utc::now() - Duration::days(7)).to_rfc3339(),
             max_date: Utc::now().to_rfc3339(),
             hash_list: vec![],
+            retention_policy: RetentionPolicy::new(config, ingest.retention_rules().rules()),
+            storage,
         }));
     }
 
@@ -336,15 +520,98 @@ async fn get_log_info(
         min_date,
         max_date,
         hash_list,
+        retention_policy: RetentionPolicy::new(config, ingest.retention_rules().rules()),
+        storage,
     }))
 }
 
+#[derive(Serialize)]
+struct RejectedLines {
+    lines: Vec<RejectedLine>,
+    rejections_by_peer: HashMap<String, u64>,
+}
+
+// Endpoint to list the most recent lines that could not be decoded
+#[get("/rejected_lines?<count>")]
+async fn get_rejected_lines(
+    count: Option<i64>,
+    db_pool: &rocket::State<SqlitePool>,
+    ingest: &rocket::State<Ingest>,
+) -> Option<Json<RejectedLines>> {
+    let lines = sqlx::query_as::<_, RejectedLine>("
+        SELECT line, error, peer, source, timestamp
+        FROM rejected_lines
+        ORDER BY id DESC
+        LIMIT ?
+    ")
+    .bind(count.unwrap_or(100))
+    .fetch_all(db_pool.inner())
+    .await
+    .ok()?;
+
+    let rejections_by_peer = ingest
+        .rejections_by_peer()
+        .await
+        .into_iter()
+        .map(|(peer, count)| (peer.to_string(), count))
+        .collect();
+
+    Some(Json(RejectedLines {
+        lines,
+        rejections_by_peer,
+    }))
+}
+
+// State of the database writer, answered with 503 while logs can't be stored
+#[get("/health")]
+fn get_health(ingest: &rocket::State<Ingest>) -> (Status, Json<WriterHealth>) {
+    let health = ingest.health();
+    let status = if health.is_degraded() { Status::ServiceUnavailable } else { Status::Ok };
+    (status, Json(health))
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    retention_sweep: SweepMetrics,
+}
+
+// Timing of the background work
+#[get("/metrics")]
+fn get_metrics(ingest: &rocket::State<Ingest>) -> Json<Metrics> {
+    Json(Metrics {
+        retention_sweep: ingest.sweep_metrics(),
+    })
+}
+
+// Retention rules for particular hashes and levels, in the order they are tried
+#[get("/retention_rules")]
+fn get_retention_rules(ingest: &rocket::State<Ingest>) -> Json<Vec<RetentionRule>> {
+    Json(ingest.retention_rules().rules())
+}
+
+// Replace the retention rules, which apply from the next maintenance run on
+#[put("/retention_rules", data = "<body>")]
+fn put_retention_rules(
+    body: String,
+    ingest: &rocket::State<Ingest>,
+) -> Result<Json<Vec<RetentionRule>>, (Status, String)> {
+    // Parsed here rather than by `Json` so a bad rule is answered with what is wrong with it
+    let rules: Vec<RetentionRule> =
+        serde_json::from_str(&body).map_err(|e| (Status::BadRequest, format!("invalid rules: {}", e)))?;
+    match ingest.retention_rules().replace(rules) {
+        Ok(rules) => Ok(Json(rules)),
+        Err(e @ ReplaceError::Invalid(_)) => Err((Status::BadRequest, e.to_string())),
+        Err(e @ ReplaceError::Save(_)) => Err((Status::InternalServerError, e.to_string())),
+    }
+}
+
 // Endpoint to purge all logs
 #[post("/purge_logs")]
-async fn purge_logs(db_pool: &rocket::State<SqlitePool>) -> Json<String> {
+async fn purge_logs(db_pool: &rocket::State<SqlitePool>, ingest: &rocket::State<Ingest>) -> Json<String> {
     let result = sqlx::query("DELETE FROM logs")
         .execute(db_pool.inner())
         .await;
+    ingest.logs_changed();
 
     match result {
         Ok(_) => Json("Logs purged successfully.".to_string()),
@@ -356,85 +623,260 @@ async fn purge_logs(db_pool: &rocket::State<SqlitePool>) -> Json<String> {
 #[post("/insert_log", data = "<log_message>")]
 async fn insert_log(
     log_message: Json<LogMessage>,
-    db_pool: &rocket::State<SqlitePool>,
-    config: &rocket::State<Config>,
-) -> Json<String> {
-    let config = config.inner();
-    let db_pool = db_pool.inner();
+    remote: RemotePeer,
+    ingest: &rocket::State<Ingest>,
+) -> Result<Json<String>, Status> {
+    if ingest.is_draining() {
+        return Err(Status::ServiceUnavailable);
+    }
+    let log_message = log_message.into_inner(); // Consume Json wrapper
+    let timestamp = log_message.timestamp.clone();
 
-    let mut log_message = log_message.into_inner(); // Consume Json wrapper
+    // Same limits, retention and retries as the log listeners
+    let outcomes = ingest
+        .submit_each(vec![log_message], &remote.0)
+        .await
+        .map_err(|ShuttingDown| Status::ServiceUnavailable)?;
 
-    // Truncate the message if it exceeds max_log_length
-    log_message.message = truncate_string(&log_message.message, config.max_log_length);
+    Ok(Json(match outcomes.first().copied().flatten() {
+        None => "Log inserted successfully.".to_string(),
+        Some(Dropped::InvalidTimestamp) => format!("Invalid timestamp {:?}. Log not inserted.", timestamp),
+        Some(Dropped::HashLimit) => "Maximum number of hashes reached. Log not inserted.".to_string(),
+        Some(Dropped::RateLimited) => "Rate limit exceeded. Log not inserted.".to_string(),
+    }))
+}
 
-    // Check if the hash exists
-    let hash_exists: bool = sqlx::query_scalar::<_, i64>("SELECT EXISTS(SELECT 1 FROM logs WHERE hash = ?)")
-        .bind(&log_message.hash)
-        .fetch_one(db_pool)
-        .await
-        .unwrap_or(0) != 0;
-
-    if !hash_exists {
-        // Get the total number of distinct hashes
-        let num_hashes: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT hash) FROM logs")
-            .fetch_one(db_pool)
-            .await
-            .unwrap_or(0);
-
-        if num_hashes >= config.max_hashes as i64 {
-            // Do not log this message
-            return Json("Maximum number of hashes reached. Log not inserted.".to_string());
+// GELF HTTP input, compatible with Graylog's `/gelf` endpoint
+#[post("/gelf", data = "<body>")]
+async fn gelf_http(
+    body: Data<'_>,
+    client_ip: Option<std::net::IpAddr>,
+    remote: RemotePeer,
+    ingest: &rocket::State<Ingest>,
+) -> Status {
+    if ingest.is_draining() {
+        return Status::ServiceUnavailable;
+    }
+    let body = match body.open(gelf::MAX_GELF_MESSAGE.bytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Status::PayloadTooLarge,
+        Err(_) => return Status::BadRequest,
+    };
+
+    match gelf::decompress(&body).and_then(|document| gelf::parse(&document, client_ip)) {
+        Some(log_message) => match ingest.submit(vec![log_message], &remote.0).await {
+            Ok(()) => Status::Accepted,
+            Err(ShuttingDown) => Status::ServiceUnavailable,
+        },
+        None => {
+            ingest.reject("gelf", &body, "not a GELF message", remote.1).await;
+            Status::BadRequest
         }
     }
+}
 
-    // Insert the log_message into the database
-    let result = sqlx::query("
-        INSERT INTO logs (level, message, target, module_path, file, line, hash, timestamp)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-    ")
-    .bind(&log_message.level)
-    .bind(&log_message.message)
-    .bind(&log_message.target)
-    .bind(&log_message.module_path)
-    .bind(&log_message.file)
-    .bind(log_message.line)
-    .bind(&log_message.hash)
-    .bind(&log_message.timestamp)
-    .execute(db_pool)
-    .await;
+// OTLP/HTTP logs receiver, accepting protobuf and JSON encoded export requests
+#[post("/v1/logs", data = "<body>")]
+async fn otlp_logs(
+    body: Data<'_>,
+    content_type: Option<&ContentType>,
+    remote: RemotePeer,
+    ingest: &rocket::State<Ingest>,
+) -> (Status, (ContentType, Vec<u8>)) {
+    use prost::Message;
+
+    let is_json = content_type.is_some_and(|content_type| content_type.is_json());
+    let reply = |status, response: otlp::ExportLogsServiceResponse| {
+        if is_json {
+            (status, (ContentType::JSON, b"{}".to_vec()))
+        } else {
+            (status, (ContentType::new("application", "x-protobuf"), response.encode_to_vec()))
+        }
+    };
 
-    match result {
-        Ok(_) => (),
-        Err(e) => return Json(format!("Failed to insert log into database: {}", e)),
+    if ingest.is_draining() {
+        return reply(Status::ServiceUnavailable, Default::default());
     }
+    let body = match body.open(otlp::MAX_OTLP_REQUEST.bytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return reply(Status::PayloadTooLarge, Default::default()),
+        Err(_) => return reply(Status::BadRequest, Default::default()),
+    };
+
+    let request = otlp::decompress(&body).and_then(|body| {
+        if is_json {
+            otlp::decode_json(&body)
+        } else {
+            otlp::decode_protobuf(&body)
+        }
+    });
 
-    // Now check if the number of logs for this hash exceeds max_log_count + 50
-    let log_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM logs WHERE hash = ?")
-        .bind(&log_message.hash)
-        .fetch_one(db_pool)
-        .await
-        .unwrap_or(0);
-
-    if log_count > (config.max_log_count + 50) as i64 {
-        // Delete the oldest 50 logs for this hash
-        let result = sqlx::query("
-            DELETE FROM logs 
-            WHERE id IN (
-                SELECT id FROM logs 
-                WHERE hash = ? 
-                ORDER BY timestamp ASC 
-                LIMIT 50
-            )
-        ")
-        .bind(&log_message.hash)
-        .execute(db_pool)
-        .await;
+    match request {
+        Some(request) => match ingest.submit(otlp::into_log_messages(request), &remote.0).await {
+            Ok(()) => reply(Status::Ok, Default::default()),
+            Err(ShuttingDown) => reply(Status::ServiceUnavailable, Default::default()),
+        },
+        None => {
+            ingest.reject("otlp", &body, "not an OTLP logs export request", remote.1).await;
+            reply(Status::BadRequest, Default::default())
+        }
+    }
+}
+
+// Loki push API, accepting snappy-compressed protobuf and JSON push requests
+#[post("/loki/api/v1/push", data = "<body>")]
+async fn loki_push(
+    body: Data<'_>,
+    content_type: Option<&ContentType>,
+    remote: RemotePeer,
+    config: &rocket::State<Config>,
+    ingest: &rocket::State<Ingest>,
+) -> Result<Status, (Status, String)> {
+    if ingest.is_draining() {
+        return Ok(Status::ServiceUnavailable);
+    }
+    let body = match body.open(loki::MAX_LOKI_REQUEST.bytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Ok(Status::PayloadTooLarge),
+        Err(_) => return Ok(Status::BadRequest),
+    };
+
+    let streams = if content_type.is_some_and(|content_type| content_type.is_json()) {
+        loki::decode_json(&body)
+    } else {
+        loki::decode_protobuf(&body)
+    };
+
+    match streams {
+        Some(streams) => {
+            let log_messages = loki::into_log_messages(streams, &config.loki_hash_labels);
+            let total = log_messages.len();
+            let Ok(outcomes) = ingest.submit_each(log_messages, &remote.0).await else {
+                return Ok(Status::ServiceUnavailable);
+            };
+            let dropped: Vec<Dropped> = outcomes.into_iter().flatten().collect();
+            if dropped.is_empty() {
+                return Ok(Status::NoContent);
+            }
+            // Like Loki, 429 has the client retry the push later, while other 4xx are final
+            let status = if dropped.contains(&Dropped::RateLimited) {
+                Status::TooManyRequests
+            } else {
+                Status::BadRequest
+            };
+            let reasons: Vec<String> = [Dropped::RateLimited, Dropped::HashLimit, Dropped::InvalidTimestamp]
+                .into_iter()
+                .filter(|reason| dropped.contains(reason))
+                .map(|reason| reason.to_string())
+                .collect();
+            Err((status, format!("{} of {} entries dropped: {}", dropped.len(), total, reasons.join(", "))))
+        }
+        None => {
+            ingest.reject("loki", &body, "not a Loki push request", remote.1).await;
+            Ok(Status::BadRequest)
+        }
+    }
+}
+
+// Elasticsearch clients (the Go one used by Beats in particular) insist on this header
+#[derive(Responder)]
+struct EsResponse {
+    inner: (Status, Json<serde_json::Value>),
+    product: Header<'static>,
+}
+
+impl EsResponse {
+    fn new(status: Status, body: serde_json::Value) -> Self {
+        EsResponse {
+            inner: (status, Json(body)),
+            product: Header::new("X-Elastic-Product", "Elasticsearch"),
+        }
+    }
+}
+
+// Elasticsearch cluster info, used by shippers to detect the version
+#[get("/")]
+fn es_info() -> EsResponse {
+    EsResponse::new(Status::Ok, elasticsearch::cluster_info())
+}
+
+#[get("/_license")]
+fn es_license() -> EsResponse {
+    EsResponse::new(Status::Ok, elasticsearch::license())
+}
+
+// Elasticsearch bulk API, accepting NDJSON index and create actions
+#[post("/_bulk", data = "<body>")]
+async fn es_bulk(
+    body: Data<'_>,
+    remote: RemotePeer,
+    config: &rocket::State<Config>,
+    ingest: &rocket::State<Ingest>,
+) -> EsResponse {
+    bulk(None, body, &remote, config, ingest).await
+}
+
+#[post("/<index>/_bulk", data = "<body>")]
+async fn es_index_bulk(
+    index: &str,
+    body: Data<'_>,
+    remote: RemotePeer,
+    config: &rocket::State<Config>,
+    ingest: &rocket::State<Ingest>,
+) -> EsResponse {
+    bulk(Some(index), body, &remote, config, ingest).await
+}
 
-        match result {
-            Ok(_) => (),
-            Err(e) => return Json(format!("Failed to delete old logs: {}", e)),
+async fn bulk(index: Option<&str>, body: Data<'_>, remote: &RemotePeer, config: &Config, ingest: &Ingest) -> EsResponse {
+    let started = std::time::Instant::now();
+    let error = |status: Status, kind: &str, reason: &str| {
+        EsResponse::new(status, serde_json::json!({
+            "error": { "type": kind, "reason": reason },
+            "status": status.code,
+        }))
+    };
+
+    if ingest.is_draining() {
+        return error(Status::ServiceUnavailable, "node_closed_exception", "the server is shutting down");
+    }
+    let body = match body.open(elasticsearch::MAX_BULK_REQUEST.bytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return error(Status::PayloadTooLarge, "content_too_long_exception", "request body is too large"),
+        Err(_) => return error(Status::BadRequest, "parse_exception", "failed to read request body"),
+    };
+    let Some(body) = elasticsearch::decompress(&body) else {
+        ingest.reject("elasticsearch", &body, "failed to decompress request body", remote.1).await;
+        return error(Status::BadRequest, "parse_exception", "failed to decompress request body");
+    };
+    let body = match String::from_utf8(body) {
+        Ok(body) => body,
+        Err(e) => {
+            ingest.reject("elasticsearch", e.as_bytes(), "request body is not valid UTF-8", remote.1).await;
+            return error(Status::BadRequest, "parse_exception", "request body is not valid UTF-8");
         }
+    };
+
+    let mut bulk = elasticsearch::process_bulk(&body, index, config);
+    for (line, reason) in &bulk.rejected {
+        ingest.reject("elasticsearch", line.as_bytes(), reason, remote.1).await;
+    }
+    let Ok(outcomes) = ingest.submit_each(std::mem::take(&mut bulk.log_messages), &remote.0).await else {
+        return error(Status::ServiceUnavailable, "node_closed_exception", "the server is shutting down");
+    };
+    // Rate limited records are worth retrying later, the others are not
+    for (record, dropped) in outcomes.into_iter().enumerate() {
+        let Some(dropped) = dropped else { continue };
+        let (status, kind) = match dropped {
+            Dropped::RateLimited => (429, "es_rejected_execution_exception"),
+            Dropped::InvalidTimestamp => (400, "mapper_parsing_exception"),
+            Dropped::HashLimit => (400, "illegal_argument_exception"),
+        };
+        bulk.fail_record(record, status, kind, &dropped.to_string());
     }
 
-    Json("Log inserted successfully.".to_string())
+    EsResponse::new(Status::Ok, serde_json::json!({
+        "took": started.elapsed().as_millis() as u64,
+        "errors": bulk.errors,
+        "items": bulk.items,
+    }))
 }
@@ -1,21 +1,36 @@
 // client_handler.rs
 
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpStream, TcpListener};
 use sqlx::SqlitePool;
 use serde_json;
 use crate::config::Config;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use once_cell::sync::Lazy;
 use tokio::sync::{Mutex, mpsc};
 use std::collections::{HashMap, VecDeque};
 use tokio::time::{interval, Duration};
-use crate::types::LogMessage;
+use crate::types::{normalize_timestamp, LogMessage, RejectedLine};
+use crate::access::{self, ConnectionGuard, ConnectionLimiter};
+use crate::shutdown::{recv_until_closed, Phase, Shutdown, ShutdownSignal};
+use crate::writer::{self, Queued, WriterHealth};
+use crate::hash_activity::{Admission, HashActivity};
+use crate::rate_limit::RateLimiter;
+use crate::retention_rules::RetentionRules;
+use crate::sweep::{self, LogCounts, SweepMetrics};
+use crate::{forward, gelf, levels, syslog};
+use crate::protocol::{
+    decode_json_records, decode_msgpack_records, parse_client_hello, Framing, HelloFrame, ServerHello,
+    CAP_MSGPACK,
+};
 
-struct LogStats {
-    hash_set: HashMap<String, usize>,
-    total_hashes: usize,
-}
+/// How often the maintenance task runs.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often records dropped by the rate limits are reported.
+const RATE_LIMIT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
 struct LogQueue {
     queue: VecDeque<LogMessage>,
@@ -53,220 +68,964 @@ pub fn truncate_string(s: &str, max_bytes: usize) -> String {
     }
 }
 
-pub async fn start_log_handler(db_pool: SqlitePool, config: Arc<Config>) {
-    let log_stats = Arc::new(Mutex::new(LogStats {
-        hash_set: HashMap::new(),
-        total_hashes: 0,
-    }));
-
-    // Initialize log_stats with existing data from the database
-    {
-        let mut stats = log_stats.lock().await;
-        let hashes: Vec<(String, i64)> = sqlx::query_as("
-            SELECT hash, COUNT(*) as count
-            FROM logs
-            GROUP BY hash
-            ORDER BY count DESC
-            LIMIT ?
-        ")
-        .bind(config.max_hashes as i64)
-        .fetch_all(&db_pool)
-        .await
-        .expect("Failed to fetch initial hashes from database");
+/// Start the database writer, maintenance task and TCP/UDP listeners.
+///
+/// Returns the ingest handle so other inputs, such as the HTTP endpoints, can feed the same
+/// pipeline, and the handle that shuts it all down without losing queued records.
+pub async fn start_log_handler(db_pool: SqlitePool, config: Arc<Config>) -> (Ingest, Shutdown) {
+    // Admit the hashes already stored, the most recently written ones if there are too many
+    let mut activity = HashActivity::new(config.max_hashes, config.hash_eviction);
+    let mut hashes: Vec<String> = sqlx::query_scalar("
+        SELECT hash
+        FROM logs
+        GROUP BY hash
+        ORDER BY MAX(id) DESC
+        LIMIT ?
+    ")
+    .bind(config.max_hashes as i64)
+    .fetch_all(&db_pool)
+    .await
+    .expect("Failed to fetch initial hashes from database");
+    hashes.reverse();
+    activity.load(hashes);
+    let hash_activity = Arc::new(Mutex::new(activity));
 
-        for (hash, count) in hashes {
-            stats.hash_set.insert(hash, count as usize);
-            stats.total_hashes += 1;
-        }
-    }
+    let connections = ConnectionLimiter::new(config.max_connections, config.max_connections_per_ip);
+    let (mut shutdown, shutdown_signal) = Shutdown::new(connections.clone());
 
-    // Create a channel for log messages
+    // Create channels for log messages and for rejected lines
     let (log_sender, log_receiver) = mpsc::channel(10000);
+    let (rejected_sender, rejected_receiver) = mpsc::channel(1000);
 
     // Spawn a background task to write logs to the database
+    let health = Arc::new(std::sync::Mutex::new(WriterHealth::new()));
+    let log_counts = Arc::new(std::sync::Mutex::new(LogCounts::new()));
     let db_writer_pool = db_pool.clone();
+    let db_writer_health = Arc::clone(&health);
+    let db_writer_counts = Arc::clone(&log_counts);
+    let db_writer_rejected = rejected_sender.clone();
     let db_writer_config = Arc::clone(&config);
-    tokio::spawn(async move {
-        database_writer(log_receiver, db_writer_pool, db_writer_config).await;
-    });
+    let db_writer_signal = shutdown_signal.clone();
+    shutdown.add_writer(tokio::spawn(async move {
+        writer::database_writer(
+            log_receiver,
+            db_writer_pool,
+            db_writer_health,
+            db_writer_counts,
+            db_writer_rejected,
+            db_writer_config,
+            db_writer_signal,
+        )
+        .await;
+    }));
+
+    // Spawn a background task to keep rejected lines for inspection
+    let rejected_writer_pool = db_pool.clone();
+    let rejected_writer_config = Arc::clone(&config);
+    let rejected_writer_signal = shutdown_signal.clone();
+    shutdown.add_writer(tokio::spawn(async move {
+        rejected_lines_writer(rejected_receiver, rejected_writer_pool, rejected_writer_config, rejected_writer_signal).await;
+    }));
+
+    let retention_rules =
+        RetentionRules::load(&config).unwrap_or_else(|e| panic!("Failed to load retention rules: {}", e));
+    let sweep_metrics = Arc::new(std::sync::Mutex::new(SweepMetrics::default()));
 
-    // Spawn a background task to periodically update the database and perform log count checks
-    let stats_clone = Arc::clone(&log_stats);
+    // Spawn a background task to periodically update the database and apply the retention limits
+    let rules_clone = retention_rules.clone();
+    let activity_clone = Arc::clone(&hash_activity);
     let pool_clone = db_pool.clone();
     let config_clone = Arc::clone(&config);
+    let health_clone = Arc::clone(&health);
+    let counts_clone = Arc::clone(&log_counts);
+    let metrics_clone = Arc::clone(&sweep_metrics);
     tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(60)); // Run every 60 seconds
+        let mut interval = interval(MAINTENANCE_INTERVAL);
+        let mut written_sample = (tokio::time::Instant::now(), 0);
         loop {
             interval.tick().await;
-            update_database(&pool_clone, &stats_clone, &config_clone).await;
-            perform_log_count_checks(&pool_clone, &config_clone).await;
+            health_clone.lock().unwrap().sample_ingest_rate(&mut written_sample);
+            sweep::sweep(&pool_clone, &rules_clone.rules(), &config_clone, &counts_clone, &metrics_clone).await;
+            forget_removed_hashes(&counts_clone, &activity_clone).await;
         }
     });
 
+    let ingest = Ingest {
+        rate_limiter: Arc::new(std::sync::Mutex::new(RateLimiter::new(config.max_hash_rate, config.max_total_rate))),
+        config: Arc::clone(&config),
+        hash_activity,
+        log_sender,
+        rejected_sender,
+        rejections_by_peer: Arc::new(Mutex::new(RejectionCounts::default())),
+        connections,
+        shutdown: shutdown_signal,
+        health,
+        retention_rules,
+        log_counts,
+        sweep_metrics,
+    };
+
+    // Periodically note in each hash's own logs how many of its records the rate limits dropped
+    if ingest.rate_limiter.lock().unwrap().is_enabled() {
+        let ingest = ingest.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(RATE_LIMIT_REPORT_INTERVAL);
+            loop {
+                interval.tick().await;
+                ingest.report_rate_limited().await;
+            }
+        });
+    }
+
+    // Start the optional syslog listeners
+    if let Some(port) = config.syslog_port {
+        let listener_addr = format!("{}:{}", config.tcp_listener_ip, port);
+        syslog::start_syslog_listeners(&listener_addr, ingest.clone()).await;
+    }
+
+    // Start the optional GELF UDP listener
+    if let Some(port) = config.gelf_udp_port {
+        let listener_addr = format!("{}:{}", config.tcp_listener_ip, port);
+        gelf::start_gelf_udp_listener(&listener_addr, ingest.clone()).await;
+    }
+
+    // Start the optional Fluent Forward listener
+    if let Some(port) = config.forward_port {
+        let listener_addr = format!("{}:{}", config.tcp_listener_ip, port);
+        forward::start_forward_listener(&listener_addr, ingest.clone()).await;
+    }
+
+    // Start the optional binary listener
+    if let Some(port) = config.binary_listener_port {
+        let listener_addr = format!("{}:{}", config.tcp_listener_ip, port);
+        let listener = TcpListener::bind(&listener_addr).await.expect("Failed to bind binary TCP listener");
+        println!("Binary log server is running on {}", listener_addr);
+
+        let ingest = ingest.clone();
+        tokio::spawn(async move {
+            accept_connections(listener, Framing::MsgPack, ingest).await;
+        });
+    }
+
     // Start TCP listener
     let listener_addr = format!("{}:{}", config.tcp_listener_ip, config.tcp_listener_port);
     let listener = TcpListener::bind(&listener_addr).await.expect("Failed to bind TCP listener");
     println!("Log server is running on {}", listener_addr);
 
-    loop {
-        let (socket, _) = listener.accept().await.expect("Failed to accept connection");
-        let db_pool = db_pool.clone();
-        let config = Arc::clone(&config);
-        let log_stats = Arc::clone(&log_stats);
-        let log_sender = log_sender.clone();
-        tokio::spawn(async move {
-            handle_client(socket, db_pool, config, log_stats, log_sender).await;
-        });
+    let tcp_ingest = ingest.clone();
+    tokio::spawn(async move {
+        accept_connections(listener, Framing::JsonLines, tcp_ingest).await;
+    });
+
+    (ingest, shutdown)
+}
+
+/// The sender of a batch of records, stored with each of them.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub ip: IpAddr,
+    pub session_id: Option<String>,
+}
+
+impl Peer {
+    /// A sender without a lasting connection, such as a UDP datagram or an HTTP request.
+    pub fn new(ip: IpAddr) -> Self {
+        Peer {
+            ip: ip.to_canonical(),
+            session_id: None,
+        }
+    }
+
+    /// A newly opened connection, given a session id of its own.
+    pub fn with_session(ip: IpAddr) -> Self {
+        static SERVER_START: Lazy<i64> = Lazy::new(|| chrono::Utc::now().timestamp());
+        static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+        Peer {
+            ip: ip.to_canonical(),
+            session_id: Some(format!("{:x}-{}", *SERVER_START, NEXT_SESSION.fetch_add(1, Ordering::Relaxed))),
+        }
     }
 }
 
-pub async fn handle_client(
-    socket: TcpStream,
-    db_pool: SqlitePool,
+/// Peers whose rejections are counted at once. UDP source addresses can be spoofed, so beyond
+/// this the peer that went longest without a rejection is forgotten to make room.
+const MAX_REJECTING_PEERS: usize = 1000;
+
+/// Rejected frames per sending IP.
+#[derive(Default)]
+struct RejectionCounts {
+    /// Rejections of each peer and the sequence number of its latest one
+    by_peer: HashMap<IpAddr, (u64, u64)>,
+    next_sequence: u64,
+}
+
+impl RejectionCounts {
+    fn record(&mut self, ip: IpAddr) {
+        if !self.by_peer.contains_key(&ip) && self.by_peer.len() >= MAX_REJECTING_PEERS {
+            let least_recent = self.by_peer.iter().min_by_key(|(_, (_, sequence))| *sequence).map(|(ip, _)| *ip);
+            if let Some(least_recent) = least_recent {
+                self.by_peer.remove(&least_recent);
+            }
+        }
+        let (count, sequence) = self.by_peer.entry(ip).or_default();
+        *count += 1;
+        *sequence = self.next_sequence;
+        self.next_sequence += 1;
+    }
+
+    fn counts(&self) -> HashMap<IpAddr, u64> {
+        self.by_peer.iter().map(|(ip, (count, _))| (*ip, *count)).collect()
+    }
+}
+
+/// Records were refused because the server is shutting down and no longer stores them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShuttingDown;
+
+impl std::fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the server is shutting down")
+    }
+}
+
+/// Why `Ingest::submit_each` dropped a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// The timestamp could not be read
+    InvalidTimestamp,
+    /// Over `--max-hash-rate` or `--max-total-rate`
+    RateLimited,
+    /// A new hash refused under `--max-hashes`
+    HashLimit,
+}
+
+impl std::fmt::Display for Dropped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dropped::InvalidTimestamp => f.write_str("invalid timestamp"),
+            Dropped::RateLimited => f.write_str("record rate limit exceeded"),
+            Dropped::HashLimit => f.write_str("hash limit reached, new hashes are refused"),
+        }
+    }
+}
+
+/// Entry point into the database pipeline shared by every input.
+#[derive(Clone)]
+pub struct Ingest {
     config: Arc<Config>,
-    log_stats: Arc<Mutex<LogStats>>,
-    log_sender: mpsc::Sender<LogMessage>,
-) {
-    let reader = BufReader::new(socket);
-    let mut lines = reader.lines();
-    
-    static LOG_INDEX: AtomicUsize = AtomicUsize::new(0);
-
-    while let Ok(Some(line)) = lines.next_line().await {
-        if let Ok(mut log_message) = serde_json::from_str::<LogMessage>(&line) {
-            let index = LOG_INDEX.fetch_add(1, Ordering::SeqCst);
-
-            log_message.message = truncate_string(&log_message.message, config.max_log_length);
-
-            let should_log = {
-                let mut stats = log_stats.lock().await;
-                if !stats.hash_set.contains_key(&log_message.hash) {
-                    if stats.total_hashes < config.max_hashes {
-                        stats.hash_set.insert(log_message.hash.clone(), 1);
-                        stats.total_hashes += 1;
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    *stats.hash_set.get_mut(&log_message.hash).unwrap() += 1;
-                    true
+    rate_limiter: Arc<std::sync::Mutex<RateLimiter>>,
+    hash_activity: Arc<Mutex<HashActivity>>,
+    log_sender: mpsc::Sender<Queued>,
+    rejected_sender: mpsc::Sender<RejectedLine>,
+    rejections_by_peer: Arc<Mutex<RejectionCounts>>,
+    connections: ConnectionLimiter,
+    shutdown: ShutdownSignal,
+    health: Arc<std::sync::Mutex<WriterHealth>>,
+    retention_rules: RetentionRules,
+    log_counts: Arc<std::sync::Mutex<LogCounts>>,
+    sweep_metrics: Arc<std::sync::Mutex<SweepMetrics>>,
+}
+
+impl Ingest {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Current state of the database writer.
+    pub fn health(&self) -> WriterHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// The retention rules the maintenance task applies.
+    pub fn retention_rules(&self) -> &RetentionRules {
+        &self.retention_rules
+    }
+
+    /// Timing of the retention sweeps.
+    pub fn sweep_metrics(&self) -> SweepMetrics {
+        self.sweep_metrics.lock().unwrap().clone()
+    }
+
+    /// Note that logs were inserted or deleted outside the pipeline, so the retention sweep
+    /// counts them again.
+    pub fn logs_changed(&self) {
+        self.log_counts.lock().unwrap().invalidate();
+    }
+
+    /// Whether `ip` passes the `--allow` and `--deny` lists.
+    pub fn is_permitted(&self, ip: IpAddr) -> bool {
+        access::is_permitted(ip, &self.config.allow, &self.config.deny)
+    }
+
+    /// Resolves once shutdown begins and listeners should stop taking new input.
+    pub async fn draining(&self) {
+        self.shutdown.reached(Phase::Draining).await;
+    }
+
+    /// Whether shutdown has begun. HTTP requests are refused from then on, like new connections.
+    pub fn is_draining(&self) -> bool {
+        self.shutdown.has_reached(Phase::Draining)
+    }
+
+    /// Accept the next connection that passes the access lists and connection limits, or
+    /// `None` once shutdown begins.
+    ///
+    /// Accept errors such as running out of file descriptors are logged and retried after a
+    /// short pause instead of taking the listener down. The guard must be held for as long as
+    /// the connection stays open.
+    pub(crate) async fn accept(&self, listener: &TcpListener) -> Option<(TcpStream, SocketAddr, ConnectionGuard)> {
+        loop {
+            let (socket, peer) = match self.shutdown.run_until(Phase::Draining, listener.accept()).await? {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
                 }
             };
+            if !self.is_permitted(peer.ip()) {
+                continue;
+            }
+            match self.connections.acquire(peer.ip()) {
+                Ok(guard) => return Some((socket, peer, guard)),
+                Err(refusal) => eprintln!("Refusing connection from {}: {}", peer, refusal),
+            }
+        }
+    }
+
+    /// Serve one connection until it closes or the shutdown grace period runs out.
+    pub(crate) fn spawn_connection(
+        &self,
+        guard: ConnectionGuard,
+        connection: impl std::future::Future<Output = ()> + Send + 'static,
+    ) {
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            shutdown.run_until(Phase::Closing, connection).await;
+            drop(guard);
+        });
+    }
+
+    /// Record a frame from `peer` that could not be decoded.
+    ///
+    /// Rejected lines are dropped rather than queued when the dead-letter writer falls behind,
+    /// so a misbehaving client can never slow down ingestion of good records.
+    pub async fn reject(&self, source: &str, line: &[u8], error: impl std::fmt::Display, peer: SocketAddr) {
+        self.rejections_by_peer.lock().await.record(peer.ip());
+
+        let rejected_line = RejectedLine {
+            line: truncate_string(&String::from_utf8_lossy(line), self.config.max_log_length),
+            error: error.to_string(),
+            peer: peer.to_string(),
+            source: source.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        let _ = self.rejected_sender.try_send(rejected_line);
+    }
+
+    /// Number of rejected frames per sending IP since the server started, for the
+    /// `MAX_REJECTING_PEERS` that were rejected most recently.
+    pub async fn rejections_by_peer(&self) -> HashMap<IpAddr, u64> {
+        self.rejections_by_peer.lock().await.counts()
+    }
+
+    /// Admit, truncate and queue records received from `peer` for the database writer.
+    ///
+    /// Fails once the writer has stopped taking records at shutdown, in which case the sender
+    /// must not be told they were stored.
+    pub async fn submit(&self, log_messages: Vec<LogMessage>, peer: &Peer) -> Result<(), ShuttingDown> {
+        self.submit_each(log_messages, peer).await.map(|_| ())
+    }
+
+    /// Like `submit`, but tells for each record, in order, whether it was dropped and why.
+    pub async fn submit_each(
+        &self,
+        log_messages: Vec<LogMessage>,
+        peer: &Peer,
+    ) -> Result<Vec<Option<Dropped>>, ShuttingDown> {
+        if self.shutdown.has_reached(Phase::Closing) {
+            return Err(ShuttingDown);
+        }
+        let mut outcomes = vec![None; log_messages.len()];
+        let mut log_messages: Vec<(usize, LogMessage)> = log_messages.into_iter().enumerate().collect();
+
+        log_messages.retain_mut(|(index, log_message)| match normalize_timestamp(&log_message.timestamp) {
+            Some(timestamp) => {
+                log_message.timestamp = timestamp;
+                true
+            }
+            None => {
+                self.reject_timestamp(log_message, peer);
+                outcomes[*index] = Some(Dropped::InvalidTimestamp);
+                false
+            }
+        });
 
-            if should_log {
-                // Send the log message to the database writer
-                if let Err(e) = log_sender.send(log_message.clone()).await {
-                    eprintln!("Failed to send log message to database writer: {}", e);
+        // Decide for the whole batch under a single lock. Evictions are queued while it is still
+        // held, so they reach the writer ahead of records for the hashes that replace them.
+        let mut refused = Vec::new();
+        {
+            let mut activity = self.hash_activity.lock().await;
+            let now = std::time::Instant::now();
+            let mut evicted = Vec::new();
+            log_messages.retain(|(index, log_message)| match activity.admit(&log_message.hash, now) {
+                Admission::Admitted => true,
+                Admission::Evicted(hash) => {
+                    evicted.push((hash, log_message.hash.clone()));
+                    true
+                }
+                Admission::Refused { notify } => {
+                    if notify {
+                        refused.push(log_message.hash.clone());
+                    }
+                    outcomes[*index] = Some(Dropped::HashLimit);
+                    false
                 }
+            });
+            for (hash, admitted) in evicted {
+                println!("Evicting least recently active hash {} to admit {}", hash, admitted);
+                self.log_sender.send(Queued::Evict(hash)).await.map_err(|_| ShuttingDown)?;
+            }
+        }
+        for hash in refused {
+            self.notify_refused_hash(hash, peer);
+        }
+
+        // Only records admitted above spend tokens, so refused hashes can't starve the others
+        {
+            let mut rate_limiter = self.rate_limiter.lock().unwrap();
+            if rate_limiter.is_enabled() {
+                let now = std::time::Instant::now();
+                log_messages.retain(|(index, log_message)| {
+                    let admitted = rate_limiter.admit(&log_message.hash, now);
+                    if !admitted {
+                        outcomes[*index] = Some(Dropped::RateLimited);
+                    }
+                    admitted
+                });
+            }
+        }
+
+        for (_, mut log_message) in log_messages {
+            log_message.message = truncate_string(&log_message.message, self.config.max_log_length);
+            let (level, severity) = levels::normalize(&log_message.level, &self.config.level_aliases);
+            log_message.level = level;
+            log_message.severity = Some(severity);
+            log_message.peer = Some(peer.ip.to_string());
+            log_message.session_id = peer.session_id.clone();
+
+            // Send the log message to the database writer, which only stops listening at shutdown
+            self.log_sender.send(Queued::Log(log_message)).await.map_err(|_| ShuttingDown)?;
+        }
+        Ok(outcomes)
+    }
+
+    /// Make a refused hash visible in the rejected lines, as its records are otherwise dropped silently.
+    fn notify_refused_hash(&self, hash: String, peer: &Peer) {
+        let error = format!(
+            "hash limit reached (--max-hashes {}), records for this hash are refused",
+            self.config.max_hashes
+        );
+        eprintln!("Refusing new hash {} from {}: {}", hash, peer.ip, error);
+        let rejected_line = RejectedLine {
+            line: hash,
+            error,
+            peer: peer.ip.to_string(),
+            source: "admission".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        let _ = self.rejected_sender.try_send(rejected_line);
+    }
+
+    /// Move a record whose timestamp can't be read to the rejected lines, as retention and
+    /// ordering depend on it.
+    fn reject_timestamp(&self, log_message: &LogMessage, peer: &Peer) {
+        let rejected_line = RejectedLine {
+            line: truncate_string(&serde_json::to_string(log_message).unwrap_or_default(), self.config.max_log_length),
+            error: format!(
+                "invalid timestamp {:?}, expected RFC 3339 or a Unix epoch",
+                log_message.timestamp
+            ),
+            peer: peer.ip.to_string(),
+            source: "timestamp".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        let _ = self.rejected_sender.try_send(rejected_line);
+    }
+
+    /// Add a warning to each hash that had records dropped by the rate limits since the last report.
+    async fn report_rate_limited(&self) {
+        let dropped = self.rate_limiter.lock().unwrap().take_dropped();
+        for (hash, count) in dropped {
+            // Hashes turned away by --max-hashes don't get one either
+            if !self.hash_activity.lock().await.contains(&hash) {
+                continue;
+            }
+            let log_message = LogMessage {
+                level: "WARN".to_string(),
+                severity: Some(4),
+                message: format!("dropped {} records due to rate limit", count),
+                target: "ironlog".to_string(),
+                module_path: None,
+                file: None,
+                line: None,
+                hash,
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                fields: None,
+                peer: None,
+                session_id: None,
+            };
+            // Bypasses the limits, otherwise the report itself would be dropped
+            if self.log_sender.send(Queued::Log(log_message)).await.is_err() {
+                break;
             }
         }
     }
 }
 
-async fn database_writer(
-    mut log_receiver: mpsc::Receiver<LogMessage>,
-    db_pool: SqlitePool,
-    config: Arc<Config>,
-) {
-    let log_queue = LogQueue::new(10000);
-    let mut batch = Vec::with_capacity(1000);
+async fn accept_connections(listener: TcpListener, framing: Framing, ingest: Ingest) {
+    while let Some((socket, peer, guard)) = ingest.accept(&listener).await {
+        ingest.spawn_connection(guard, handle_client(socket, peer, framing, ingest.clone()));
+    }
+}
+
+/// How much of an oversized frame is kept in the dead-letter table.
+pub(crate) const OVERSIZED_SAMPLE_LENGTH: usize = 1024;
 
-    while let Some(log_message) = log_receiver.recv().await {
-        batch.push(log_message);
+/// Outcome of reading one newline-terminated frame.
+pub(crate) enum LineRead {
+    Line,
+    Eof,
+    /// The line went past the limit; the buffer holds its first bytes and the rest is unread.
+    TooLong,
+}
+
+/// Read one line into `line` without ever buffering more than `max_length` bytes of it.
+pub(crate) async fn read_bounded_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    max_length: usize,
+) -> std::io::Result<LineRead> {
+    line.clear();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(if line.is_empty() { LineRead::Eof } else { LineRead::Line });
+        }
+
+        let (chunk, done) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => (&available[..newline], newline + 1),
+            None => (available, available.len()),
+        };
+        if line.len() + chunk.len() > max_length {
+            let keep = max_length.saturating_sub(line.len()).min(chunk.len());
+            line.extend_from_slice(&chunk[..keep]);
+            reader.consume(keep);
+            return Ok(LineRead::TooLong);
+        }
+
+        let found_newline = chunk.len() < done;
+        line.extend_from_slice(chunk);
+        reader.consume(done);
+        if found_newline {
+            return Ok(LineRead::Line);
+        }
+    }
+}
 
-        // If the batch is full or we haven't received a message for a while, flush the batch
-        if batch.len() >= 1000 || log_receiver.is_empty() {
-            write_logs_to_database(&batch, &db_pool, &config).await;
-            batch.clear();
+/// Discard input up to and including the next newline, returning the number of bytes skipped.
+pub(crate) async fn skip_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<u64> {
+    let mut skipped = 0;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(skipped);
+        }
+        match available.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => {
+                reader.consume(newline + 1);
+                return Ok(skipped + newline as u64 + 1);
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+                skipped += len as u64;
+            }
         }
     }
 }
 
-async fn write_logs_to_database(logs: &[LogMessage], db_pool: &SqlitePool, config: &Config) {
-    let mut transaction = db_pool.begin().await.expect("Failed to begin transaction");
+/// Outcome of reading the body of one length-prefixed frame.
+pub(crate) enum FrameRead {
+    Frame(Vec<u8>),
+    /// The frame went past the limit; this holds its first bytes and the rest is unread.
+    TooLong(Vec<u8>),
+}
 
-    for log in logs {
-        sqlx::query("
-            INSERT INTO logs (level, message, target, module_path, file, line, hash, timestamp)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-        ")
-        .bind(&log.level)
-        .bind(&log.message)
-        .bind(&log.target)
-        .bind(&log.module_path)
-        .bind(&log.file)
-        .bind(log.line)
-        .bind(&log.hash)
-        .bind(&log.timestamp)
-        .execute(&mut *transaction)
-        .await
-        .expect("Failed to insert log into database.");
+/// Read the `frame_length` byte body following a length prefix, or only the start of it when
+/// it is longer than `max_length`.
+pub(crate) async fn read_frame_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    frame_length: usize,
+    max_length: usize,
+) -> std::io::Result<FrameRead> {
+    if frame_length > max_length {
+        let mut sample = vec![0; frame_length.min(OVERSIZED_SAMPLE_LENGTH)];
+        reader.read_exact(&mut sample).await?;
+        Ok(FrameRead::TooLong(sample))
+    } else {
+        let mut frame = vec![0; frame_length];
+        reader.read_exact(&mut frame).await?;
+        Ok(FrameRead::Frame(frame))
+    }
+}
+
+/// Discard the next `length` bytes without buffering them.
+pub(crate) async fn skip_bytes<R: AsyncRead + Unpin>(reader: &mut R, length: u64) -> std::io::Result<()> {
+    let skipped = tokio::io::copy(&mut reader.take(length), &mut tokio::io::sink()).await?;
+    if skipped < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Fail a read that takes longer than the idle timeout, if one is configured.
+pub(crate) async fn with_idle_timeout<T>(
+    idle_timeout: Option<Duration>,
+    read: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match idle_timeout {
+        Some(idle_timeout) => tokio::time::timeout(idle_timeout, read)
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "idle timeout"))),
+        None => read.await,
     }
+}
+
+/// Token bucket that keeps a connection under a number of bytes per second.
+///
+/// It pauses reading rather than dropping data, so a fast sender is slowed down by TCP's own
+/// flow control once the socket buffers fill up. Up to one second's worth may be read in a burst.
+pub(crate) struct ReadRateLimiter {
+    bytes_per_second: u64,
+    /// Point in time at which everything read so far has been paid for.
+    paid_until: tokio::time::Instant,
+}
+
+impl ReadRateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        ReadRateLimiter {
+            bytes_per_second: bytes_per_second.max(1),
+            paid_until: tokio::time::Instant::now(),
+        }
+    }
+
+    pub(crate) async fn consume(&mut self, bytes: usize) {
+        let now = tokio::time::Instant::now();
+        let burst_start = now.checked_sub(Duration::from_secs(1)).unwrap_or(now);
+        self.paid_until = self.paid_until.max(burst_start)
+            + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        if self.paid_until > now {
+            tokio::time::sleep_until(self.paid_until).await;
+        }
+    }
+}
+
+pub async fn handle_client(socket: TcpStream, peer: SocketAddr, mut framing: Framing, ingest: Ingest) {
+    let config = ingest.config();
+    let idle_timeout = config.idle_timeout.map(Duration::from_secs);
+    let mut rate_limiter = config.max_read_rate.map(ReadRateLimiter::new);
+    let (read_half, mut write_half) = socket.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut line = Vec::new();
+    let mut first_frame = true;
+    let session = Peer::with_session(peer.ip());
+
+    loop {
+        let (log_messages, frame_length) = match framing {
+            Framing::JsonLines => {
+                let read = with_idle_timeout(idle_timeout, read_bounded_line(&mut reader, &mut line, config.max_frame_length));
+                match read.await {
+                    Ok(LineRead::Line) => {}
+                    Ok(LineRead::TooLong) => {
+                        line.truncate(OVERSIZED_SAMPLE_LENGTH);
+                        let error = format!("line longer than {} bytes", config.max_frame_length);
+                        ingest.reject("json", &line, error, peer).await;
+                        if config.disconnect_oversized {
+                            break;
+                        }
+                        let Ok(skipped) = with_idle_timeout(idle_timeout, skip_line(&mut reader)).await else { break };
+                        // Skipped bytes count against the read rate too
+                        if let Some(rate_limiter) = rate_limiter.as_mut() {
+                            rate_limiter.consume(line.len() + skipped as usize).await;
+                        }
+                        continue;
+                    }
+                    Ok(LineRead::Eof) => break,
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::TimedOut {
+                            println!("Closing idle connection from {}", peer);
+                        }
+                        break;
+                    }
+                }
+
+                let Ok(text) = std::str::from_utf8(&line) else {
+                    ingest.reject("json", &line, "line is not valid UTF-8", peer).await;
+                    continue;
+                };
+                let text = text.trim_end_matches(['\r', '\n']);
+                if text.trim().is_empty() {
+                    continue;
+                }
+
+                // Only the first line of a connection may be a hello frame
+                if std::mem::take(&mut first_frame) {
+                    if let Some(hello) = parse_client_hello(text) {
+                        let reply = HelloFrame {
+                            hello: ServerHello::reply_to(&hello, config.max_log_length, config.max_frame_length),
+                        };
+                        let mut reply_bytes = serde_json::to_vec(&reply).expect("Failed to serialize server hello");
+                        reply_bytes.push(b'\n');
+                        if write_half.write_all(&reply_bytes).await.is_err() {
+                            break;
+                        }
+                        if reply.hello.has_capability(CAP_MSGPACK) {
+                            framing = Framing::MsgPack;
+                        }
+                        continue;
+                    }
+                }
+
+                match decode_json_records(text) {
+                    Ok(log_messages) => (log_messages, line.len()),
+                    Err(e) => {
+                        ingest.reject("json", text.as_bytes(), e, peer).await;
+                        continue;
+                    }
+                }
+            }
+            Framing::MsgPack => {
+                let frame_length = match with_idle_timeout(idle_timeout, reader.read_u32()).await {
+                    Ok(length) => length as usize,
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::TimedOut {
+                            println!("Closing idle connection from {}", peer);
+                        }
+                        break;
+                    }
+                };
+                let read = read_frame_body(&mut reader, frame_length, config.max_frame_length);
+                match with_idle_timeout(idle_timeout, read).await {
+                    Ok(FrameRead::TooLong(sample)) => {
+                        let error = format!("{} byte frame is longer than {} bytes", frame_length, config.max_frame_length);
+                        ingest.reject("msgpack", &sample, error, peer).await;
+
+                        // The length prefix tells us where the next frame starts, so it can be skipped unread
+                        let skip = skip_bytes(&mut reader, (frame_length - sample.len()) as u64);
+                        if config.disconnect_oversized || with_idle_timeout(idle_timeout, skip).await.is_err() {
+                            break;
+                        }
+                        (Vec::new(), 4 + frame_length)
+                    }
+                    Ok(FrameRead::Frame(frame)) => match decode_msgpack_records(&frame) {
+                        Ok(log_messages) => (log_messages, 4 + frame_length),
+                        Err(e) => {
+                            ingest.reject("msgpack", &frame, e, peer).await;
+                            continue;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        };
+
+        if !log_messages.is_empty() && ingest.submit(log_messages, &session).await.is_err() {
+            break;
+        }
 
-    transaction.commit().await.expect("Failed to commit transaction");
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            rate_limiter.consume(frame_length).await;
+        }
+    }
 }
 
-async fn update_database(db_pool: &SqlitePool, log_stats: &Arc<Mutex<LogStats>>, config: &Config) {
-    let mut stats = log_stats.lock().await;
-
-    // Print when writing to the database
-    // Reset the hash set if it's too large
-    if stats.total_hashes > config.max_hashes {
-        stats.hash_set.clear();
-        stats.total_hashes = 0;
-
-        // Repopulate with current hashes from the database
-        let hashes: Vec<(String, i64)> = sqlx::query_as("
-            SELECT hash, COUNT(*) as count
-            FROM logs
-            GROUP BY hash
-            ORDER BY count DESC
-            LIMIT ?
+async fn rejected_lines_writer(
+    mut rejected_receiver: mpsc::Receiver<RejectedLine>,
+    db_pool: SqlitePool,
+    config: Arc<Config>,
+    shutdown: ShutdownSignal,
+) {
+    let mut closed = false;
+    while let Some(rejected_line) = recv_until_closed(&mut rejected_receiver, &shutdown, &mut closed).await {
+        let result = sqlx::query("
+            INSERT INTO rejected_lines (line, error, peer, source, timestamp)
+            VALUES (?, ?, ?, ?, ?)
         ")
-        .bind(config.max_hashes as i64)
-        .fetch_all(db_pool)
-        .await
-        .expect("Failed to fetch hashes from database");
+        .bind(&rejected_line.line)
+        .bind(&rejected_line.error)
+        .bind(&rejected_line.peer)
+        .bind(&rejected_line.source)
+        .bind(&rejected_line.timestamp)
+        .execute(&db_pool)
+        .await;
 
-        for (hash, count) in hashes {
-            stats.hash_set.insert(hash, count as usize);
-            stats.total_hashes += 1;
+        if let Err(e) = result {
+            eprintln!("Failed to store rejected line: {}", e);
+            continue;
+        }
+
+        // Keep only the newest max_rejected_lines entries
+        let result = sqlx::query("
+            DELETE FROM rejected_lines
+            WHERE id <= (SELECT MAX(id) FROM rejected_lines) - ?
+        ")
+        .bind(config.max_rejected_lines as i64)
+        .execute(&db_pool)
+        .await;
+
+        if let Err(e) = result {
+            eprintln!("Failed to trim rejected lines: {}", e);
         }
     }
 }
 
-async fn perform_log_count_checks(db_pool: &SqlitePool, config: &Config) {
-    let hashes: Vec<String> = sqlx::query_scalar("SELECT DISTINCT hash FROM logs")
-        .fetch_all(db_pool)
+// Stop counting hashes whose logs are gone, e.g. purged, against --max-hashes. The sweep keeps
+// the counts current, so this runs right after it.
+async fn forget_removed_hashes(log_counts: &std::sync::Mutex<LogCounts>, hash_activity: &Mutex<HashActivity>) {
+    let Some(stored) = log_counts.lock().unwrap().stored_hashes() else { return };
+    hash_activity
+        .lock()
         .await
-        .expect("Failed to fetch hashes");
+        .forget_removed(&stored, MAINTENANCE_INTERVAL, std::time::Instant::now());
+}
 
-    for hash in hashes {
-        let log_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM logs WHERE hash = ?")
-            .bind(&hash)
-            .fetch_one(db_pool)
-            .await
-            .unwrap_or(0);
-
-        if log_count > config.max_log_count as i64 {
-            let logs_to_delete = log_count - config.max_log_count as i64;
-            
-            sqlx::query("
-                DELETE FROM logs 
-                WHERE id IN (
-                    SELECT id FROM logs 
-                    WHERE hash = ? 
-                    ORDER BY timestamp ASC 
-                    LIMIT ?
-                )
-            ")
-            .bind(&hash)
-            .bind(logs_to_delete)
-            .execute(db_pool)
-            .await
-            .expect("Failed to delete old logs.");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn reads_lines_up_to_the_limit() {
+        block_on(async {
+            // A tiny buffer makes lines arrive in several pieces
+            let mut reader = BufReader::with_capacity(4, &b"short\nexactly10!\nmuch too long\nlast"[..]);
+            let mut line = Vec::new();
+
+            assert!(matches!(read_bounded_line(&mut reader, &mut line, 10).await, Ok(LineRead::Line)));
+            assert_eq!(line, b"short");
+            assert!(matches!(read_bounded_line(&mut reader, &mut line, 10).await, Ok(LineRead::Line)));
+            assert_eq!(line, b"exactly10!");
+            assert!(matches!(read_bounded_line(&mut reader, &mut line, 10).await, Ok(LineRead::TooLong)));
+            assert_eq!(line, b"much too l");
+            assert_eq!(skip_line(&mut reader).await.unwrap(), 4);
+            assert!(matches!(read_bounded_line(&mut reader, &mut line, 10).await, Ok(LineRead::Line)));
+            assert_eq!(line, b"last");
+            assert!(matches!(read_bounded_line(&mut reader, &mut line, 10).await, Ok(LineRead::Eof)));
+        });
+    }
+
+    #[test]
+    fn reads_msgpack_frames_and_skips_oversized_ones() {
+        block_on(async {
+            let record = |message: &str| {
+                serde_json::json!({"level": "INFO", "message": message, "target": "app", "module_path": null, "file": null, "line": null, "hash": "h"})
+            };
+            let mut stream = crate::protocol::encode_msgpack_frame(&record("first")).unwrap();
+            stream.extend_from_slice(&crate::protocol::frame_msgpack(&[0xaa; 2000]));
+            stream.extend_from_slice(&crate::protocol::encode_msgpack_frame(&record("second")).unwrap());
+            let mut reader = &stream[..];
+
+            let length = reader.read_u32().await.unwrap() as usize;
+            let Ok(FrameRead::Frame(frame)) = read_frame_body(&mut reader, length, 100).await else { panic!("expected a frame") };
+            assert_eq!(decode_msgpack_records(&frame).unwrap()[0].message, "first");
+
+            let length = reader.read_u32().await.unwrap() as usize;
+            assert_eq!(length, 2000);
+            let Ok(FrameRead::TooLong(sample)) = read_frame_body(&mut reader, length, 100).await else { panic!("expected an oversized frame") };
+            assert_eq!(sample.len(), OVERSIZED_SAMPLE_LENGTH);
+            skip_bytes(&mut reader, (length - sample.len()) as u64).await.unwrap();
+
+            let length = reader.read_u32().await.unwrap() as usize;
+            let Ok(FrameRead::Frame(frame)) = read_frame_body(&mut reader, length, 100).await else { panic!("expected a frame") };
+            assert_eq!(decode_msgpack_records(&frame).unwrap()[0].message, "second");
+            assert!(reader.is_empty());
+        });
+    }
+
+    #[test]
+    fn fails_on_truncated_frames() {
+        block_on(async {
+            let mut reader = &[1u8, 2, 3][..];
+            assert!(read_frame_body(&mut reader, 10, 100).await.is_err());
+            let mut reader = &[1u8, 2, 3][..];
+            assert!(skip_bytes(&mut reader, 10).await.is_err());
+        });
+    }
+
+    #[test]
+    fn skips_to_the_end_without_a_newline() {
+        block_on(async {
+            let mut reader = BufReader::with_capacity(4, &b"no newline at all"[..]);
+            assert_eq!(skip_line(&mut reader).await.unwrap(), 17);
+            assert_eq!(skip_line(&mut reader).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn paces_reads_at_the_configured_rate() {
+        block_on(async {
+            let mut limiter = ReadRateLimiter::new(10_000);
+            let start = tokio::time::Instant::now();
+            limiter.consume(1_000).await;
+            assert!(start.elapsed() >= Duration::from_millis(90));
+            assert!(start.elapsed() < Duration::from_millis(500));
+        });
+    }
 
+    #[test]
+    fn allows_a_burst_after_being_idle() {
+        block_on(async {
+            // As if the connection had been quiet for a while
+            let start = tokio::time::Instant::now();
+            let mut limiter = ReadRateLimiter { bytes_per_second: 10_000, paid_until: start - Duration::from_secs(5) };
+            limiter.consume(9_000).await;
+            assert!(start.elapsed() < Duration::from_millis(50), "up to a second's worth is free");
+            limiter.consume(2_000).await;
+            assert!(start.elapsed() >= Duration::from_millis(90), "the rest is paced");
+        });
+    }
+
+    #[test]
+    fn forgets_the_peers_rejected_least_recently() {
+        let mut rejections = RejectionCounts::default();
+        let peer = |n: usize| IpAddr::from([10, 0, (n / 256) as u8, (n % 256) as u8]);
+        for n in 0..MAX_REJECTING_PEERS {
+            rejections.record(peer(n));
         }
+        // Peer 0 is rejected again, so peer 1 is now the least recent
+        rejections.record(peer(0));
+        rejections.record(peer(MAX_REJECTING_PEERS));
+
+        let counts = rejections.counts();
+        assert_eq!(counts.len(), MAX_REJECTING_PEERS);
+        assert_eq!(counts.get(&peer(0)), Some(&2));
+        assert_eq!(counts.get(&peer(1)), None);
+        assert_eq!(counts.get(&peer(2)), Some(&1));
+        assert_eq!(counts.get(&peer(MAX_REJECTING_PEERS)), Some(&1));
+    }
+
+    #[test]
+    fn times_out_idle_reads() {
+        block_on(async {
+            let never = std::future::pending::<std::io::Result<()>>();
+            let result = with_idle_timeout(Some(Duration::from_millis(10)), never).await;
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+            assert!(with_idle_timeout(None, async { Ok(()) }).await.is_ok());
+        });
     }
 }
@@ -1,5 +1,23 @@
 // config.rs
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::access::Cidr;
+use crate::levels::parse_alias;
+use crate::protocol::MAX_FRAME_LENGTH;
+use crate::retention::{ByteSize, RetentionPeriod};
+
+/// What happens when a new hash arrives while `--max-hashes` hashes are already stored.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashEviction {
+    /// Refuse the new hash and note it in the rejected lines
+    Reject,
+    /// Delete the logs of the least recently active hash to make room
+    Delete,
+    /// Move the logs of the least recently active hash to the `archived_logs` table
+    Archive,
+}
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about)]
@@ -16,6 +34,46 @@ pub struct Config {
     #[clap(long, default_value = "5000")]
     pub tcp_listener_port: u16,
 
+    /// Optional TCP port that speaks length-prefixed MessagePack frames from the first byte
+    #[clap(long)]
+    pub binary_listener_port: Option<u16>,
+
+    /// Optional port to accept syslog (RFC 5424 / RFC 3164) on, over both UDP and TCP
+    #[clap(long)]
+    pub syslog_port: Option<u16>,
+
+    /// Hash assigned to syslog records; {hostname}, {app} and {facility} are substituted
+    #[clap(long, default_value = "{hostname}")]
+    pub syslog_hash: String,
+
+    /// Optional UDP port to accept GELF (chunked and compressed) on
+    #[clap(long)]
+    pub gelf_udp_port: Option<u16>,
+
+    /// Optional TCP port to accept the Fluent Forward protocol on (Fluent Bit / Fluentd `forward` output)
+    #[clap(long)]
+    pub forward_port: Option<u16>,
+
+    /// Labels tried in order for the hash of Loki push streams
+    #[clap(long, value_delimiter = ',', default_value = "service_name,app,job,container_name")]
+    pub loki_hash_labels: Vec<String>,
+
+    /// Document field holding the message in Elasticsearch bulk requests
+    #[clap(long, default_value = "message")]
+    pub es_message_field: String,
+
+    /// Document field holding the level in Elasticsearch bulk requests
+    #[clap(long, default_value = "log.level")]
+    pub es_level_field: String,
+
+    /// Document field holding the hash in Elasticsearch bulk requests; the index name is used when absent
+    #[clap(long, default_value = "host.name")]
+    pub es_hash_field: String,
+
+    /// Document field holding the timestamp in Elasticsearch bulk requests
+    #[clap(long, default_value = "@timestamp")]
+    pub es_timestamp_field: String,
+
     /// API server IP
     #[clap(long, default_value = "127.0.0.1")]
     pub api_server_ip: String,
@@ -28,11 +86,93 @@ pub struct Config {
     #[clap(long, default_value = "1000")]
     pub max_hashes: usize,
 
+    /// What to do with a new hash once `--max-hashes` is reached
+    #[clap(long, value_enum, default_value_t = HashEviction::Reject)]
+    pub hash_eviction: HashEviction,
+
     /// Max number of logs per hash
     #[clap(long, default_value = "500")]
     pub max_log_count: usize,
 
+    /// Optional age after which logs are deleted, e.g. `14d` (units: s, m, h, d, w)
+    #[clap(long, value_name = "PERIOD")]
+    pub retention: Option<RetentionPeriod>,
+
+    /// Optional JSON file with retention rules for particular hashes and levels, which are
+    /// also editable through `/api/retention_rules`
+    #[clap(long, value_name = "FILE")]
+    pub retention_rules: Option<PathBuf>,
+
+    /// Optional size budget for the database, e.g. `2G` (units: K, M, G). The oldest logs are
+    /// deleted to stay under it
+    #[clap(long, value_name = "SIZE")]
+    pub max_db_size: Option<ByteSize>,
+
     //max length of a log message
     #[clap(long, default_value = "1000")]
     pub max_log_length: usize,
+
+    /// Extra level spelling to accept, e.g. `--level-alias SEVERE=ERROR` (repeatable)
+    #[clap(long = "level-alias", value_name = "NAME=LEVEL", value_parser = parse_alias)]
+    pub level_aliases: Vec<(String, String)>,
+
+    /// Largest line or binary frame read from a log connection, in bytes
+    #[clap(long, default_value_t = MAX_FRAME_LENGTH)]
+    pub max_frame_length: usize,
+
+    /// Close connections that send an oversized frame instead of skipping the frame
+    #[clap(long)]
+    pub disconnect_oversized: bool,
+
+    /// Optional per-connection read limit, in bytes per second
+    #[clap(long)]
+    pub max_read_rate: Option<u64>,
+
+    /// Optional limit on records stored per hash, in records per second (excess is dropped)
+    #[clap(long)]
+    pub max_hash_rate: Option<u64>,
+
+    /// Optional limit on records stored across all hashes, in records per second
+    #[clap(long)]
+    pub max_total_rate: Option<u64>,
+
+    /// Optional number of seconds after which a silent connection is closed
+    #[clap(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Optional limit on open log connections across all TCP listeners
+    #[clap(long)]
+    pub max_connections: Option<usize>,
+
+    /// Optional limit on open log connections from a single IP
+    #[clap(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Only accept clients in this network, e.g. `10.0.0.0/8` (repeatable; applies to the API too)
+    #[clap(long = "allow", value_name = "CIDR")]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients in this network, e.g. `192.168.1.0/24` (repeatable; applies to the API too)
+    #[clap(long = "deny", value_name = "CIDR")]
+    pub deny: Vec<Cidr>,
+
+    /// Max number of rejected (undecodable) lines kept for inspection
+    #[clap(long, default_value = "1000")]
+    pub max_rejected_lines: usize,
+
+    /// Seconds open connections get to finish sending after SIGTERM before they are closed
+    #[clap(long, default_value = "5")]
+    pub shutdown_grace: u64,
+
+    /// Max number of logs held in memory while the database cannot be written to (e.g. disk full)
+    #[clap(long, default_value = "100000")]
+    pub max_buffered_logs: usize,
+
+    /// Max number of logs written to the database in one transaction
+    #[clap(long, default_value = "1000")]
+    pub flush_batch_size: usize,
+
+    /// Milliseconds a log may wait for its batch to fill before it is written anyway
+    #[clap(long, default_value = "100")]
+    pub flush_interval_ms: u64,
 }
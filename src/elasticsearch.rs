@@ -0,0 +1,375 @@
+// elasticsearch.rs
+
+//! Minimal Elasticsearch bulk API, so Beats and Vector can ship to ironlog unmodified.
+//!
+//! Only `index` and `create` actions are stored; any other action is answered with an error
+//! item. Documents are mapped into `LogMessage` through the `--es-*-field` options, which
+//! name the (optionally dotted) document fields holding the message, level, hash and
+//! timestamp. Everything else in the document is kept in `fields`.
+
+use std::io::Read;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use sqlx::types::Json;
+
+use crate::config::Config;
+use crate::types::{Fields, LogMessage};
+
+/// Largest (decompressed) bulk request accepted.
+pub const MAX_BULK_REQUEST: usize = 16 * 1024 * 1024;
+
+/// Version reported to clients that check which Elasticsearch they talk to.
+pub const COMPATIBLE_VERSION: &str = "8.11.0";
+
+/// Records and per-action results of one bulk request.
+pub struct Bulk {
+    pub log_messages: Vec<LogMessage>,
+    pub items: Vec<Value>,
+    pub errors: bool,
+    /// Lines that could not be decoded and why, for the rejected lines
+    pub rejected: Vec<(String, &'static str)>,
+    /// Position in `items` of each record in `log_messages`
+    record_items: Vec<usize>,
+}
+
+impl Bulk {
+    /// Report the `record`th record as failed after all, e.g. when it was dropped by a limit.
+    pub fn fail_record(&mut self, record: usize, status: u16, kind: &str, reason: &str) {
+        let item = self.items[self.record_items[record]].as_object_mut().and_then(|item| item.values_mut().next());
+        if let Some(Value::Object(result)) = item {
+            result.retain(|key, _| key == "_index" || key == "_id");
+            result.insert("status".to_string(), json!(status));
+            result.insert("error".to_string(), json!({ "type": kind, "reason": reason }));
+        }
+        self.errors = true;
+    }
+}
+
+/// Undo gzip compression if present.
+pub fn decompress(body: &[u8]) -> Option<Vec<u8>> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Some(body.to_vec());
+    }
+    let mut decoded = Vec::new();
+    GzDecoder::new(body)
+        .take(MAX_BULK_REQUEST as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= MAX_BULK_REQUEST).then_some(decoded)
+}
+
+/// Body of `GET /`, which shippers use to detect the cluster version.
+pub fn cluster_info() -> Value {
+    json!({
+        "name": "ironlog",
+        "cluster_name": "ironlog",
+        "version": {
+            "number": COMPATIBLE_VERSION,
+            "build_flavor": "default",
+            "lucene_version": "9.8.0",
+            "minimum_wire_compatibility_version": "7.17.0",
+            "minimum_index_compatibility_version": "7.0.0",
+        },
+        "tagline": "You Know, for Search",
+    })
+}
+
+/// Body of `GET /_license`, checked by Beats before they start shipping.
+pub fn license() -> Value {
+    json!({
+        "license": {
+            "status": "active",
+            "uid": "ironlog",
+            "type": "basic",
+            "mode": "basic",
+        }
+    })
+}
+
+/// Process an NDJSON bulk body. `default_index` comes from `/<index>/_bulk`.
+pub fn process_bulk(body: &str, default_index: Option<&str>, config: &Config) -> Bulk {
+    let mut bulk = Bulk {
+        log_messages: Vec::new(),
+        items: Vec::new(),
+        errors: false,
+        rejected: Vec::new(),
+        record_items: Vec::new(),
+    };
+    let mut lines = body.lines().filter(|line| !line.trim().is_empty());
+
+    while let Some(action_line) = lines.next() {
+        let action = match serde_json::from_str::<Value>(action_line) {
+            Ok(Value::Object(action)) if action.len() == 1 => action,
+            _ => {
+                // Without a valid action the rest of the body cannot be paired up reliably
+                bulk.errors = true;
+                bulk.items.push(error_item("index", "", "illegal_argument_exception", "malformed action/metadata line"));
+                bulk.rejected.push((action_line.to_string(), "malformed action/metadata line"));
+                break;
+            }
+        };
+        let Some((name, metadata)) = action.into_iter().next() else { break };
+        let index = metadata
+            .get("_index")
+            .and_then(Value::as_str)
+            .or(default_index)
+            .unwrap_or("")
+            .to_string();
+
+        match name.as_str() {
+            "index" | "create" => {
+                let document_line = lines.next();
+                let log_message = match document_line.map(serde_json::from_str::<Value>) {
+                    Some(Ok(Value::Object(document))) => into_log_message(document, &index, config),
+                    _ => {
+                        bulk.errors = true;
+                        bulk.items.push(error_item(&name, &index, "mapper_parsing_exception", "failed to parse document"));
+                        bulk.rejected.push((document_line.unwrap_or(action_line).to_string(), "failed to parse document"));
+                        continue;
+                    }
+                };
+                let id = metadata
+                    .get("_id")
+                    .and_then(Value::as_str)
+                    .map_or_else(|| generate_id(bulk.items.len()), str::to_string);
+
+                bulk.items.push(json!({
+                    name.as_str(): {
+                        "_index": index,
+                        "_id": id,
+                        "_version": 1,
+                        "result": "created",
+                        "_shards": { "total": 1, "successful": 1, "failed": 0 },
+                        "_seq_no": bulk.log_messages.len(),
+                        "_primary_term": 1,
+                        "status": 201,
+                    }
+                }));
+                bulk.record_items.push(bulk.items.len() - 1);
+                bulk.log_messages.push(log_message);
+            }
+            other => {
+                // `update` carries a document line, `delete` does not
+                if other == "update" {
+                    lines.next();
+                }
+                bulk.errors = true;
+                bulk.items.push(error_item(other, &index, "illegal_argument_exception", "only index and create actions are supported"));
+            }
+        }
+    }
+
+    bulk
+}
+
+fn error_item(action: &str, index: &str, kind: &str, reason: &str) -> Value {
+    json!({
+        action: {
+            "_index": index,
+            "status": 400,
+            "error": { "type": kind, "reason": reason },
+        }
+    })
+}
+
+fn generate_id(position: usize) -> String {
+    let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{:x}{:04x}", nanos, position)
+}
+
+/// Remove a field by its literal name or, failing that, by walking a dotted path.
+fn take_field(document: &mut Fields, path: &str) -> Option<Value> {
+    if let Some(value) = document.remove(path) {
+        return Some(value);
+    }
+    let (parent, rest) = path.split_once('.')?;
+    let Value::Object(child) = document.get_mut(parent)? else { return None };
+    let value = take_field(child, rest)?;
+    // Don't leave `"log": {}` behind once `log.level` is taken
+    if child.is_empty() {
+        document.remove(parent);
+    }
+    Some(value)
+}
+
+fn into_log_message(mut document: Fields, index: &str, config: &Config) -> LogMessage {
+    let text = |value: Value| match value {
+        Value::String(text) => text,
+        other => other.to_string(),
+    };
+
+    let message = take_field(&mut document, &config.es_message_field).map(text);
+    let level = take_field(&mut document, &config.es_level_field).map_or_else(|| "INFO".to_string(), text);
+    let hash = take_field(&mut document, &config.es_hash_field)
+        .map(text)
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| if index.is_empty() { "elasticsearch".to_string() } else { index.to_string() });
+    let timestamp = match take_field(&mut document, &config.es_timestamp_field) {
+        Some(Value::String(timestamp)) => DateTime::parse_from_rfc3339(&timestamp)
+            .ok()
+            .map(|timestamp| timestamp.with_timezone(&Utc)),
+        // Numbers are epoch milliseconds, like ES's default date format
+        Some(Value::Number(millis)) => millis.as_i64().and_then(DateTime::from_timestamp_millis),
+        _ => None,
+    };
+    // Without a message field the whole document is the message
+    let message = message.unwrap_or_else(|| Value::Object(std::mem::take(&mut document)).to_string());
+
+    LogMessage {
+        level,
+        severity: None,
+        message,
+        target: "elasticsearch".to_string(),
+        module_path: None,
+        file: None,
+        line: None,
+        hash,
+        timestamp: timestamp.unwrap_or_else(Utc::now).to_rfc3339(),
+        fields: (!document.is_empty()).then_some(Json(document)),
+        peer: None,
+        session_id: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config() -> Config {
+        Config::parse_from(["ironlog"])
+    }
+
+    fn action(item: &Value) -> (&str, &Value) {
+        let (name, result) = item.as_object().unwrap().iter().next().unwrap();
+        (name.as_str(), result)
+    }
+
+    #[test]
+    fn pairs_actions_with_documents() {
+        let body = concat!(
+            r#"{"index":{"_index":"web","_id":"a"}}"#, "\n",
+            r#"{"message":"first","log":{"level":"warn"},"host":{"name":"web-1"},"@timestamp":"2024-05-01T12:00:00+02:00"}"#, "\n",
+            "\n",
+            r#"{"create":{}}"#, "\n",
+            r#"{"message":"second","@timestamp":1714557600000}"#, "\n",
+        );
+        let bulk = process_bulk(body, Some("default"), &config());
+
+        assert!(!bulk.errors);
+        assert_eq!(bulk.log_messages.len(), 2);
+        let first = &bulk.log_messages[0];
+        assert_eq!(first.message, "first");
+        assert_eq!(first.level, "warn");
+        assert_eq!(first.hash, "web-1");
+        assert_eq!(first.timestamp, "2024-05-01T10:00:00+00:00");
+        assert!(first.fields.is_none());
+        let second = &bulk.log_messages[1];
+        assert_eq!(second.hash, "default");
+        assert_eq!(second.timestamp, "2024-05-01T10:00:00+00:00");
+
+        let (name, result) = action(&bulk.items[0]);
+        assert_eq!((name, result["_id"].as_str(), result["status"].as_u64()), ("index", Some("a"), Some(201)));
+        let (name, result) = action(&bulk.items[1]);
+        assert_eq!((name, result["_index"].as_str()), ("create", Some("default")));
+    }
+
+    #[test]
+    fn answers_update_and_delete_with_errors() {
+        let body = concat!(
+            r#"{"update":{"_index":"web","_id":"a"}}"#, "\n",
+            r#"{"doc":{"message":"changed"}}"#, "\n",
+            r#"{"delete":{"_index":"web","_id":"b"}}"#, "\n",
+            r#"{"index":{}}"#, "\n",
+            r#"{"message":"kept"}"#, "\n",
+        );
+        let bulk = process_bulk(body, None, &config());
+
+        assert!(bulk.errors);
+        assert_eq!(bulk.log_messages.len(), 1);
+        assert_eq!(bulk.log_messages[0].message, "kept");
+        assert_eq!(bulk.log_messages[0].hash, "elasticsearch");
+        let names: Vec<&str> = bulk.items.iter().map(|item| action(item).0).collect();
+        assert_eq!(names, ["update", "delete", "index"]);
+        assert_eq!(action(&bulk.items[0]).1["status"], 400);
+        assert_eq!(action(&bulk.items[1]).1["status"], 400);
+        assert!(bulk.rejected.is_empty());
+    }
+
+    #[test]
+    fn stops_at_a_malformed_action() {
+        let body = concat!(
+            r#"{"index":{}}"#, "\n",
+            r#"{"message":"stored"}"#, "\n",
+            r#"not an action"#, "\n",
+            r#"{"index":{}}"#, "\n",
+            r#"{"message":"never read"}"#, "\n",
+        );
+        let bulk = process_bulk(body, None, &config());
+
+        assert!(bulk.errors);
+        assert_eq!(bulk.log_messages.len(), 1);
+        assert_eq!(bulk.items.len(), 2);
+        assert_eq!(bulk.rejected, [("not an action".to_string(), "malformed action/metadata line")]);
+    }
+
+    #[test]
+    fn rejects_unparseable_documents() {
+        let body = concat!(r#"{"index":{}}"#, "\n", "{broken", "\n", r#"{"index":{}}"#, "\n");
+        let bulk = process_bulk(body, None, &config());
+
+        assert!(bulk.errors);
+        assert!(bulk.log_messages.is_empty());
+        assert_eq!(bulk.rejected.len(), 2);
+        assert_eq!(bulk.rejected[0].0, "{broken");
+        // A trailing action without its document
+        assert_eq!(bulk.rejected[1].0, r#"{"index":{}}"#);
+    }
+
+    #[test]
+    fn takes_dotted_fields() {
+        let mut document = match json!({
+            "log": { "level": "error", "file": { "path": "/var/log/app" } },
+            "log.origin": "literal",
+        }) {
+            Value::Object(document) => document,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(take_field(&mut document, "log.origin"), Some(json!("literal")));
+        assert_eq!(take_field(&mut document, "log.level"), Some(json!("error")));
+        assert_eq!(take_field(&mut document, "log.file.path"), Some(json!("/var/log/app")));
+        assert_eq!(take_field(&mut document, "log.missing"), None);
+        // Emptied parents are removed
+        assert!(document.is_empty());
+    }
+
+    #[test]
+    fn fails_records_after_the_fact() {
+        let body = concat!(
+            r#"{"index":{"_id":"a"}}"#, "\n", "{broken", "\n",
+            r#"{"index":{"_id":"b"}}"#, "\n", r#"{"message":"one"}"#, "\n",
+            r#"{"create":{"_index":"web","_id":"c"}}"#, "\n", r#"{"message":"two"}"#, "\n",
+        );
+        let mut bulk = process_bulk(body, None, &config());
+        bulk.errors = false;
+
+        bulk.fail_record(1, 429, "es_rejected_execution_exception", "rate limited");
+
+        assert!(bulk.errors);
+        assert_eq!(action(&bulk.items[1]).1["status"], 201);
+        assert_eq!(
+            bulk.items[2],
+            json!({
+                "create": {
+                    "_index": "web",
+                    "_id": "c",
+                    "status": 429,
+                    "error": { "type": "es_rejected_execution_exception", "reason": "rate limited" },
+                }
+            })
+        );
+    }
+}
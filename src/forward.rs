@@ -0,0 +1,502 @@
+// forward.rs
+
+//! Fluent Forward protocol ingestion over TCP, for Fluent Bit and Fluentd `forward` outputs.
+//!
+//! Message, Forward and PackedForward (optionally gzip compressed) modes are understood. The
+//! tag becomes the hash, and when the sender sets the `chunk` option the message is
+//! acknowledged with `{"ack": chunk}` once all its records are queued. Shared key authentication
+//! and TLS are not supported.
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use rmp::Marker;
+use rmpv::Value;
+use serde_json::json;
+use sqlx::types::Json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::client_handler::{with_idle_timeout, Ingest, Peer, ReadRateLimiter};
+use crate::types::{Fields, LogMessage};
+
+/// Largest forward message accepted, after decompression.
+pub const MAX_FORWARD_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// Record keys that may carry the log line, in order of preference.
+const MESSAGE_KEYS: [&str; 3] = ["log", "message", "msg"];
+/// Record keys that may carry the level, in order of preference.
+const LEVEL_KEYS: [&str; 3] = ["level", "severity", "log_level"];
+
+/// Records of one forward message and the chunk id to acknowledge, if any.
+#[derive(Debug)]
+pub struct Forwarded {
+    pub log_messages: Vec<LogMessage>,
+    pub chunk: Option<Value>,
+}
+
+/// Length of the first MessagePack value in `buf`, or `None` until all of it has arrived.
+pub fn value_length(buf: &[u8]) -> Result<Option<usize>, &'static str> {
+    ValueScanner::new().scan(buf)
+}
+
+/// Finds the end of the first MessagePack value in a buffer that grows as data arrives.
+///
+/// Only headers are walked, and each call resumes where the previous one stopped, so a large
+/// message received in many reads is only walked once.
+struct ValueScanner {
+    /// Start of the next header, or end of the value once `remaining` is 0
+    pos: usize,
+    /// Values still to be walked
+    remaining: usize,
+}
+
+impl ValueScanner {
+    fn new() -> Self {
+        ValueScanner { pos: 0, remaining: 1 }
+    }
+
+    /// Length of the first value in `buf`, which must start with everything passed to earlier
+    /// calls. Once a length is returned, the scanner starts over for the next value.
+    fn scan(&mut self, buf: &[u8]) -> Result<Option<usize>, &'static str> {
+        while self.remaining > 0 {
+            let Some(&marker) = buf.get(self.pos) else { return Ok(None) };
+            let marker = Marker::from_u8(marker);
+            if matches!(marker, Marker::Reserved) {
+                return Err("invalid MessagePack marker");
+            }
+            let Some((skip, children)) = value_header(marker, &buf[self.pos + 1..]) else { return Ok(None) };
+            self.pos += 1 + skip;
+            self.remaining = (self.remaining - 1).saturating_add(children);
+        }
+        if self.pos > buf.len() {
+            return Ok(None);
+        }
+        Ok(Some(std::mem::take(self).pos))
+    }
+}
+
+impl Default for ValueScanner {
+    fn default() -> Self {
+        ValueScanner::new()
+    }
+}
+
+/// Bytes to skip after `marker` and the number of nested values that follow them.
+fn value_header(marker: Marker, rest: &[u8]) -> Option<(usize, usize)> {
+    let length = |width: usize| {
+        let bytes = rest.get(..width)?;
+        Some(bytes.iter().fold(0, |length, byte| (length << 8) | *byte as usize))
+    };
+
+    Some(match marker {
+        Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False | Marker::Reserved => (0, 0),
+        Marker::U8 | Marker::I8 => (1, 0),
+        Marker::U16 | Marker::I16 => (2, 0),
+        Marker::U32 | Marker::I32 | Marker::F32 => (4, 0),
+        Marker::U64 | Marker::I64 | Marker::F64 => (8, 0),
+        Marker::FixStr(len) => (len as usize, 0),
+        Marker::Str8 | Marker::Bin8 => (1 + length(1)?, 0),
+        Marker::Str16 | Marker::Bin16 => (2 + length(2)?, 0),
+        Marker::Str32 | Marker::Bin32 => (4 + length(4)?, 0),
+        Marker::FixArray(len) => (0, len as usize),
+        Marker::Array16 => (2, length(2)?),
+        Marker::Array32 => (4, length(4)?),
+        Marker::FixMap(len) => (0, 2 * len as usize),
+        Marker::Map16 => (2, 2 * length(2)?),
+        Marker::Map32 => (4, 2 * length(4)?),
+        Marker::FixExt1 => (2, 0),
+        Marker::FixExt2 => (3, 0),
+        Marker::FixExt4 => (5, 0),
+        Marker::FixExt8 => (9, 0),
+        Marker::FixExt16 => (17, 0),
+        Marker::Ext8 => (2 + length(1)?, 0),
+        Marker::Ext16 => (3 + length(2)?, 0),
+        Marker::Ext32 => (5 + length(4)?, 0),
+    })
+}
+
+/// Decode one forward message in any of the three modes.
+pub fn parse(message: Value) -> Result<Forwarded, String> {
+    let Value::Array(mut parts) = message else {
+        return Err("expected an array".to_string());
+    };
+    if parts.len() < 2 {
+        return Err("expected at least a tag and entries".to_string());
+    }
+    let tag = match parts.remove(0) {
+        Value::String(tag) => tag.into_str().ok_or("tag is not valid UTF-8")?,
+        _ => return Err("tag is not a string".to_string()),
+    };
+
+    let mut log_messages = Vec::new();
+    let option = match parts.remove(0) {
+        // Forward mode: [tag, [[time, record], ...], option]
+        Value::Array(entries) => {
+            for entry in entries {
+                log_messages.push(parse_entry(&tag, entry)?);
+            }
+            parts.into_iter().next()
+        }
+        // PackedForward mode: [tag, <concatenated [time, record] entries>, option]
+        entries @ (Value::Binary(_) | Value::String(_)) => {
+            let option = parts.into_iter().next();
+            let entries = entries.as_slice().unwrap_or_default();
+            let entries = match option_value(option.as_ref(), "compressed").and_then(Value::as_str) {
+                Some("gzip") => {
+                    let mut decompressed = Vec::new();
+                    MultiGzDecoder::new(entries)
+                        .take(MAX_FORWARD_MESSAGE as u64 + 1)
+                        .read_to_end(&mut decompressed)
+                        .map_err(|e| format!("invalid gzip entries: {}", e))?;
+                    if decompressed.len() > MAX_FORWARD_MESSAGE {
+                        return Err("decompressed entries too large".to_string());
+                    }
+                    decompressed
+                }
+                Some(other) => return Err(format!("unsupported compression `{}`", other)),
+                None => entries.to_vec(),
+            };
+
+            let mut reader = entries.as_slice();
+            while !reader.is_empty() {
+                let entry = rmpv::decode::read_value(&mut reader).map_err(|e| format!("invalid packed entry: {}", e))?;
+                log_messages.push(parse_entry(&tag, entry)?);
+            }
+            option
+        }
+        // Message mode: [tag, time, record, option]
+        time => {
+            let mut parts = parts.into_iter();
+            let record = parts.next().ok_or("missing record")?;
+            log_messages.push(into_log_message(&tag, &time, record)?);
+            parts.next()
+        }
+    };
+
+    Ok(Forwarded {
+        log_messages,
+        chunk: option_value(option.as_ref(), "chunk").cloned(),
+    })
+}
+
+fn option_value<'a>(option: Option<&'a Value>, key: &str) -> Option<&'a Value> {
+    option?
+        .as_map()?
+        .iter()
+        .find(|(name, _)| name.as_str() == Some(key))
+        .map(|(_, value)| value)
+}
+
+fn parse_entry(tag: &str, entry: Value) -> Result<LogMessage, String> {
+    let Value::Array(entry) = entry else {
+        return Err("entry is not a [time, record] array".to_string());
+    };
+    let mut entry = entry.into_iter();
+    let time = entry.next().ok_or("entry is missing its time")?;
+    let record = entry.next().ok_or("entry is missing its record")?;
+    into_log_message(tag, &time, record)
+}
+
+/// Event time as an integer, a float or the EventTime extension (type 0).
+fn timestamp(time: &Value) -> Option<DateTime<Utc>> {
+    match time {
+        Value::Ext(0, data) if data.len() == 8 => {
+            let seconds = u32::from_be_bytes(data[..4].try_into().ok()?);
+            let nanos = u32::from_be_bytes(data[4..].try_into().ok()?);
+            DateTime::from_timestamp(seconds as i64, nanos)
+        }
+        Value::Integer(seconds) => DateTime::from_timestamp(seconds.as_i64()?, 0),
+        Value::F32(_) | Value::F64(_) => {
+            let seconds = time.as_f64()?;
+            DateTime::from_timestamp(seconds.trunc() as i64, (seconds.fract() * 1e9) as u32)
+        }
+        _ => None,
+    }
+}
+
+/// Map one record onto a `LogMessage`, keeping keys other than the message and level as fields.
+fn into_log_message(tag: &str, time: &Value, record: Value) -> Result<LogMessage, String> {
+    let Value::Map(record) = record else {
+        return Err("record is not a map".to_string());
+    };
+    let mut fields = Fields::new();
+    for (key, value) in record {
+        let key = match key {
+            Value::String(key) => key.into_str().unwrap_or_default(),
+            other => other.to_string(),
+        };
+        fields.insert(key, to_json(value));
+    }
+
+    let message = MESSAGE_KEYS.iter().find_map(|key| fields.remove(*key));
+    let message = match message {
+        Some(serde_json::Value::String(message)) => message,
+        Some(other) => other.to_string(),
+        // Without a recognizable message key, the whole record is the message
+        None => serde_json::Value::Object(std::mem::take(&mut fields)).to_string(),
+    };
+    let level = match LEVEL_KEYS.iter().find_map(|key| fields.remove(*key)) {
+        Some(serde_json::Value::String(level)) => level,
+        Some(other) => other.to_string(),
+        None => "INFO".to_string(),
+    };
+
+    Ok(LogMessage {
+        level,
+        severity: None,
+        message: message.trim_end_matches(['\r', '\n']).to_string(),
+        target: "forward".to_string(),
+        module_path: None,
+        file: None,
+        line: None,
+        hash: tag.to_string(),
+        timestamp: timestamp(time).unwrap_or_else(Utc::now).to_rfc3339(),
+        fields: (!fields.is_empty()).then_some(Json(fields)),
+        peer: None,
+        session_id: None,
+    })
+}
+
+fn to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(value) => json!(value),
+        Value::Integer(value) => match value.as_i64() {
+            Some(value) => json!(value),
+            None => json!(value.as_u64()),
+        },
+        Value::F32(value) => json!(value),
+        Value::F64(value) => json!(value),
+        Value::String(value) => json!(String::from_utf8_lossy(value.as_bytes())),
+        // Older Fluentd versions send strings as raw bytes
+        Value::Binary(value) => json!(String::from_utf8_lossy(&value)),
+        Value::Array(values) => serde_json::Value::Array(values.into_iter().map(to_json).collect()),
+        Value::Map(entries) => serde_json::Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Value::String(key) => key.into_str().unwrap_or_default(),
+                        other => other.to_string(),
+                    };
+                    (key, to_json(value))
+                })
+                .collect(),
+        ),
+        Value::Ext(_, _) => json!(value.to_string()),
+    }
+}
+
+/// Bind the forward listener on `listener_addr` and feed it into `ingest`.
+pub(crate) async fn start_forward_listener(listener_addr: &str, ingest: Ingest) {
+    let listener = TcpListener::bind(listener_addr).await.expect("Failed to bind forward TCP listener");
+    println!("Fluent Forward server is running on {}", listener_addr);
+
+    tokio::spawn(async move {
+        while let Some((socket, peer, guard)) = ingest.accept(&listener).await {
+            ingest.spawn_connection(guard, handle_client(socket, peer, ingest.clone()));
+        }
+    });
+}
+
+async fn handle_client(mut socket: TcpStream, peer: SocketAddr, ingest: Ingest) {
+    let idle_timeout = ingest.config().idle_timeout.map(Duration::from_secs);
+    let mut rate_limiter = ingest.config().max_read_rate.map(ReadRateLimiter::new);
+    let mut buf = Vec::new();
+    let mut read_buf = vec![0; 64 * 1024];
+    let mut scanner = ValueScanner::new();
+    let session = Peer::with_session(peer.ip());
+
+    loop {
+        // Handle every complete message received so far
+        loop {
+            let length = match scanner.scan(&buf) {
+                Ok(Some(length)) => length,
+                Ok(None) => break,
+                Err(error) => {
+                    ingest.reject("forward", &buf, error, peer).await;
+                    return;
+                }
+            };
+            let message: Vec<u8> = buf.drain(..length).collect();
+
+            let forwarded = rmpv::decode::read_value(&mut message.as_slice())
+                .map_err(|e| e.to_string())
+                .and_then(parse);
+            match forwarded {
+                Ok(forwarded) => {
+                    // Without an ack the sender keeps the chunk and retries it elsewhere or later
+                    let Ok(outcomes) = ingest.submit_each(forwarded.log_messages, &session).await else {
+                        return;
+                    };
+                    // That includes chunks with records dropped by a limit. Records of the chunk
+                    // that were stored are stored again when it is resent.
+                    let all_stored = outcomes.iter().all(Option::is_none);
+                    if let Some(chunk) = forwarded.chunk.filter(|_| all_stored) {
+                        let mut ack = Vec::new();
+                        let reply = Value::Map(vec![(Value::from("ack"), chunk)]);
+                        rmpv::encode::write_value(&mut ack, &reply).expect("Writing to a Vec cannot fail");
+                        if socket.write_all(&ack).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(error) => ingest.reject("forward", &message, error, peer).await,
+            }
+        }
+
+        if buf.len() > MAX_FORWARD_MESSAGE {
+            ingest.reject("forward", &buf, "message too large", peer).await;
+            return;
+        }
+        let len = match with_idle_timeout(idle_timeout, socket.read(&mut read_buf)).await {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    println!("Closing idle connection from {}", peer);
+                }
+                return;
+            }
+        };
+        buf.extend_from_slice(&read_buf[..len]);
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            rate_limiter.consume(len).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, value).unwrap();
+        buf
+    }
+
+    fn record(message: &str) -> Value {
+        Value::Map(vec![
+            (Value::from("log"), Value::from(format!("{}\n", message))),
+            (Value::from("level"), Value::from("warn")),
+            (Value::from("pod"), Value::from("web-1")),
+        ])
+    }
+
+    fn entry(seconds: i64, message: &str) -> Value {
+        Value::Array(vec![Value::from(seconds), record(message)])
+    }
+
+    #[test]
+    fn finds_the_end_of_complete_values_only() {
+        let message = encode(&Value::Array(vec![
+            Value::from("app"),
+            Value::Array(vec![entry(1, "one"), entry(2, &"x".repeat(300))]),
+        ]));
+        for end in 0..message.len() {
+            assert_eq!(value_length(&message[..end]), Ok(None), "prefix of {} bytes", end);
+        }
+        let mut buf = message.clone();
+        buf.extend_from_slice(&encode(&Value::from(7)));
+        assert_eq!(value_length(&buf), Ok(Some(message.len())));
+    }
+
+    #[test]
+    fn resumes_scanning_as_data_arrives() {
+        let first = encode(&Value::Array(vec![Value::from("app"), Value::from("x".repeat(70_000))]));
+        let second = encode(&Value::Map(vec![(Value::from("k"), Value::Nil)]));
+        let stream = [first.clone(), second.clone()].concat();
+
+        let mut scanner = ValueScanner::new();
+        let mut buf = Vec::new();
+        let mut lengths = Vec::new();
+        for piece in stream.chunks(1000) {
+            buf.extend_from_slice(piece);
+            while let Some(length) = scanner.scan(&buf).unwrap() {
+                lengths.push(length);
+                buf.drain(..length);
+            }
+        }
+        assert_eq!(lengths, [first.len(), second.len()]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_markers() {
+        assert!(value_length(&[0xc1]).is_err());
+        assert!(value_length(&[0x92, 0x01, 0xc1]).is_err());
+    }
+
+    #[test]
+    fn parses_message_mode() {
+        let message = Value::Array(vec![
+            Value::from("app.web"),
+            Value::from(1_700_000_000),
+            record("hello"),
+            Value::Map(vec![(Value::from("chunk"), Value::from("abc"))]),
+        ]);
+        let forwarded = parse(message).unwrap();
+        assert_eq!(forwarded.chunk, Some(Value::from("abc")));
+        let log = &forwarded.log_messages[0];
+        assert_eq!(log.hash, "app.web");
+        assert_eq!(log.message, "hello");
+        assert_eq!(log.level, "warn");
+        assert_eq!(log.timestamp, "2023-11-14T22:13:20+00:00");
+        assert_eq!(log.fields.as_ref().unwrap().0["pod"], json!("web-1"));
+    }
+
+    #[test]
+    fn parses_forward_mode() {
+        let message = Value::Array(vec![Value::from("app"), Value::Array(vec![entry(1, "one"), entry(2, "two")])]);
+        let forwarded = parse(message).unwrap();
+        assert_eq!(forwarded.chunk, None);
+        let messages: Vec<&str> = forwarded.log_messages.iter().map(|log| log.message.as_str()).collect();
+        assert_eq!(messages, ["one", "two"]);
+    }
+
+    #[test]
+    fn parses_packed_forward_mode() {
+        let entries = [encode(&entry(1, "one")), encode(&entry(2, "two"))].concat();
+        let forwarded = parse(Value::Array(vec![Value::from("app"), Value::Binary(entries.clone())])).unwrap();
+        assert_eq!(forwarded.log_messages.len(), 2);
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&entries).unwrap();
+        let option = Value::Map(vec![
+            (Value::from("compressed"), Value::from("gzip")),
+            (Value::from("chunk"), Value::from("c1")),
+        ]);
+        let message = Value::Array(vec![Value::from("app"), Value::Binary(encoder.finish().unwrap()), option]);
+        let forwarded = parse(message).unwrap();
+        assert_eq!(forwarded.log_messages[1].message, "two");
+        assert_eq!(forwarded.chunk, Some(Value::from("c1")));
+    }
+
+    #[test]
+    fn reads_event_time() {
+        let mut time = 1_700_000_000u32.to_be_bytes().to_vec();
+        time.extend_from_slice(&500_000_000u32.to_be_bytes());
+        let message = Value::Array(vec![Value::from("app"), Value::Ext(0, time), record("hi")]);
+        assert_eq!(parse(message).unwrap().log_messages[0].timestamp, "2023-11-14T22:13:20.500+00:00");
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(parse(Value::from("app")).is_err());
+        assert!(parse(Value::Array(vec![Value::from(1), Value::Array(vec![])])).is_err());
+        assert!(parse(Value::Array(vec![Value::from("app"), Value::Array(vec![Value::from(1)])])).is_err());
+        let packed = Value::Array(vec![
+            Value::from("app"),
+            Value::Binary(vec![0x91]),
+            Value::Map(vec![(Value::from("compressed"), Value::from("zstd"))]),
+        ]);
+        assert!(parse(packed).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+// gelf.rs
+
+//! GELF (Graylog Extended Log Format) ingestion over UDP and HTTP.
+//!
+//! UDP datagrams may be chunked and gzip or zlib compressed. Chunks of one message are
+//! reassembled for up to five seconds, after which the incomplete message is dropped. Message
+//! IDs are chosen by the sender, so at most 1000 incomplete messages and 32 MiB of chunks are
+//! held, dropping the oldest messages first.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::Value;
+use sqlx::types::Json;
+use tokio::net::UdpSocket;
+
+use crate::client_handler::{Ingest, Peer};
+use crate::levels::syslog_level;
+use crate::types::{Fields, LogMessage};
+
+/// Largest decompressed GELF message accepted.
+pub const MAX_GELF_MESSAGE: usize = 1024 * 1024;
+
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const CHUNK_HEADER_LENGTH: usize = 12;
+const MAX_CHUNKS: usize = 128;
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PENDING_MESSAGES: usize = 1000;
+const MAX_PENDING_BYTES: usize = 32 * 1024 * 1024;
+
+/// Undo gzip or zlib compression if present. Plain JSON is returned as is.
+pub fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let limit = MAX_GELF_MESSAGE as u64 + 1;
+    match payload {
+        [0x1f, 0x8b, ..] => {
+            GzDecoder::new(payload).take(limit).read_to_end(&mut decoded).ok()?;
+        }
+        [0x78, ..] => {
+            ZlibDecoder::new(payload).take(limit).read_to_end(&mut decoded).ok()?;
+        }
+        _ => decoded.extend_from_slice(payload),
+    }
+    (decoded.len() <= MAX_GELF_MESSAGE).then_some(decoded)
+}
+
+/// Map a GELF JSON document onto a `LogMessage`.
+///
+/// `host` becomes the hash, falling back to `peer` when absent. Additional fields (`_name`)
+/// are stored in `fields` without their leading underscore, next to `full_message`.
+pub fn parse(document: &[u8], peer: Option<IpAddr>) -> Option<LogMessage> {
+    let Ok(Value::Object(mut document)) = serde_json::from_slice::<Value>(document) else {
+        return None;
+    };
+
+    let message = match document.remove("short_message")? {
+        Value::String(message) => message,
+        other => other.to_string(),
+    };
+    let hash = match document.remove("host") {
+        Some(Value::String(host)) if !host.is_empty() => host,
+        _ => peer.map(|peer| peer.to_string()).unwrap_or_else(|| "gelf".to_string()),
+    };
+    // GELF levels are syslog severities and default to ALERT
+    let severity = document.remove("level").and_then(|level| level.as_u64()).unwrap_or(1);
+    let timestamp = document
+        .remove("timestamp")
+        .and_then(|timestamp| timestamp.as_f64())
+        .and_then(|seconds| DateTime::from_timestamp(seconds.trunc() as i64, (seconds.fract() * 1e9) as u32))
+        .unwrap_or_else(Utc::now);
+    let target = match document.remove("facility") {
+        Some(Value::String(facility)) => facility,
+        _ => "gelf".to_string(),
+    };
+    let file = match document.remove("file") {
+        Some(Value::String(file)) => Some(file),
+        _ => None,
+    };
+    let line = document.remove("line").and_then(|line| line.as_i64());
+
+    let mut fields = Fields::new();
+    if let Some(full_message) = document.remove("full_message") {
+        fields.insert("full_message".to_string(), full_message);
+    }
+    for (key, value) in document {
+        // `_id` is reserved by the spec
+        if let Some(name) = key.strip_prefix('_').filter(|name| *name != "id") {
+            fields.insert(name.to_string(), value);
+        }
+    }
+
+    Some(LogMessage {
+        level: syslog_level(severity.min(7) as u8).to_string(),
+        severity: None,
+        message,
+        target,
+        module_path: None,
+        file,
+        line,
+        hash,
+        timestamp: timestamp.to_rfc3339(),
+        fields: (!fields.is_empty()).then_some(Json(fields)),
+        peer: None,
+        session_id: None,
+    })
+}
+
+struct PartialMessage {
+    first_seen: Instant,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl PartialMessage {
+    fn buffered(&self) -> usize {
+        self.chunks.iter().flatten().map(Vec::len).sum()
+    }
+}
+
+/// Collects the chunks of chunked GELF datagrams.
+#[derive(Default)]
+pub struct ChunkReassembler {
+    pending: HashMap<[u8; 8], PartialMessage>,
+    /// Pending message IDs by arrival of their first chunk, oldest first. Entries of messages
+    /// that have since completed are skipped when they come up.
+    arrivals: VecDeque<(Instant, [u8; 8])>,
+    /// Bytes held in `pending`
+    buffered: usize,
+}
+
+impl ChunkReassembler {
+    /// Feed one datagram. Returns the complete payload once every chunk has arrived,
+    /// or straight away for datagrams that are not chunked.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        self.push_at(datagram, Instant::now())
+    }
+
+    fn push_at(&mut self, datagram: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if !datagram.starts_with(&CHUNK_MAGIC) {
+            return Some(datagram.to_vec());
+        }
+        if datagram.len() < CHUNK_HEADER_LENGTH {
+            return None;
+        }
+
+        let expired = |(first_seen, _): &(Instant, [u8; 8])| now.duration_since(*first_seen) >= CHUNK_TIMEOUT;
+        while self.arrivals.front().is_some_and(expired) {
+            self.drop_oldest();
+        }
+
+        let id: [u8; 8] = datagram[2..10].try_into().ok()?;
+        let sequence = datagram[10] as usize;
+        let count = datagram[11] as usize;
+        if count == 0 || count > MAX_CHUNKS || sequence >= count {
+            return None;
+        }
+
+        if !self.pending.contains_key(&id) {
+            while self.pending.len() >= MAX_PENDING_MESSAGES {
+                self.drop_oldest();
+            }
+            self.pending.insert(id, PartialMessage { first_seen: now, chunks: vec![None; count] });
+            self.arrivals.push_back((now, id));
+        }
+        let partial = self.pending.get_mut(&id)?;
+        if partial.chunks.len() != count {
+            return None;
+        }
+        let chunk = datagram[CHUNK_HEADER_LENGTH..].to_vec();
+        self.buffered += chunk.len();
+        if let Some(replaced) = partial.chunks[sequence].replace(chunk) {
+            self.buffered -= replaced.len();
+        }
+
+        if partial.chunks.iter().all(Option::is_some) {
+            let partial = self.pending.remove(&id)?;
+            self.buffered -= partial.buffered();
+            return Some(partial.chunks.into_iter().flatten().flatten().collect());
+        }
+        // May drop this very message, if it is the oldest
+        while self.buffered > MAX_PENDING_BYTES {
+            self.drop_oldest();
+        }
+        None
+    }
+
+    /// Forget the message whose first chunk arrived earliest.
+    fn drop_oldest(&mut self) {
+        while let Some((first_seen, id)) = self.arrivals.pop_front() {
+            // A message that completed and then reappeared under the same ID has a newer entry
+            if self.pending.get(&id).is_some_and(|partial| partial.first_seen == first_seen) {
+                if let Some(partial) = self.pending.remove(&id) {
+                    self.buffered -= partial.buffered();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Bind the GELF UDP listener on `listener_addr` and feed it into `ingest`.
+pub(crate) async fn start_gelf_udp_listener(listener_addr: &str, ingest: Ingest) {
+    let socket = UdpSocket::bind(listener_addr).await.expect("Failed to bind GELF UDP socket");
+    println!("GELF server is running on {} (UDP)", listener_addr);
+
+    tokio::spawn(async move {
+        let mut reassembler = ChunkReassembler::default();
+        let mut buf = vec![0; 65536];
+        loop {
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buf) => received,
+                _ = ingest.draining() => break,
+            };
+            let (len, peer): (usize, SocketAddr) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive GELF datagram: {}", e);
+                    continue;
+                }
+            };
+
+            if !ingest.is_permitted(peer.ip()) {
+                continue;
+            }
+            let Some(payload) = reassembler.push(&buf[..len]) else { continue };
+            match decompress(&payload).and_then(|document| parse(&document, Some(peer.ip()))) {
+                Some(log_message) => {
+                    let _ = ingest.submit(vec![log_message], &Peer::new(peer.ip())).await;
+                }
+                None => ingest.reject("gelf", &payload, "not a GELF message", peer).await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: u64, sequence: u8, count: u8, data: &[u8]) -> Vec<u8> {
+        let mut datagram = CHUNK_MAGIC.to_vec();
+        datagram.extend_from_slice(&id.to_be_bytes());
+        datagram.extend_from_slice(&[sequence, count]);
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    #[test]
+    fn passes_unchunked_datagrams_through() {
+        let mut reassembler = ChunkReassembler::default();
+        assert_eq!(reassembler.push(b"{}"), Some(b"{}".to_vec()));
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut reassembler = ChunkReassembler::default();
+        assert_eq!(reassembler.push(&chunk(1, 2, 3, b"c")), None);
+        assert_eq!(reassembler.push(&chunk(1, 0, 3, b"a")), None);
+        assert_eq!(reassembler.push(&chunk(1, 1, 3, b"b")), Some(b"abc".to_vec()));
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn rejects_bad_sequence_numbers() {
+        let mut reassembler = ChunkReassembler::default();
+        assert_eq!(reassembler.push(&chunk(1, 3, 3, b"x")), None);
+        assert_eq!(reassembler.push(&chunk(1, 0, 0, b"x")), None);
+        assert_eq!(reassembler.push(&chunk(1, 0, 129, b"x")), None);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let mut reassembler = ChunkReassembler::default();
+        let start = Instant::now();
+        reassembler.push_at(&chunk(1, 0, 2, b"a"), start);
+        reassembler.push_at(&chunk(2, 0, 2, b"b"), start + Duration::from_secs(3));
+        assert_eq!(reassembler.push_at(&chunk(1, 1, 2, b"a"), start + CHUNK_TIMEOUT), None);
+        assert_eq!(reassembler.push_at(&chunk(2, 1, 2, b"b"), start + CHUNK_TIMEOUT), Some(b"bb".to_vec()));
+        // Only the restarted message 1 is left
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.buffered, 1);
+    }
+
+    #[test]
+    fn caps_pending_messages_dropping_the_oldest() {
+        let mut reassembler = ChunkReassembler::default();
+        for id in 0..MAX_PENDING_MESSAGES as u64 + 10 {
+            reassembler.push(&chunk(id, 0, 2, b"x"));
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_MESSAGES);
+        assert!(!reassembler.pending.contains_key(&9u64.to_be_bytes()));
+        assert_eq!(reassembler.push(&chunk(10, 1, 2, b"x")), Some(b"xx".to_vec()));
+    }
+
+    #[test]
+    fn caps_buffered_bytes_dropping_the_oldest() {
+        let mut reassembler = ChunkReassembler::default();
+        let data = vec![0; 60_000];
+        for id in 0..1000 {
+            reassembler.push(&chunk(id, 0, 2, &data));
+        }
+        assert!(reassembler.buffered <= MAX_PENDING_BYTES);
+        let held: usize = reassembler.pending.values().map(PartialMessage::buffered).sum();
+        assert_eq!(reassembler.buffered, held);
+        assert!(!reassembler.pending.contains_key(&0u64.to_be_bytes()));
+        assert!(reassembler.pending.contains_key(&999u64.to_be_bytes()));
+    }
+}
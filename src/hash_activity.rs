@@ -0,0 +1,160 @@
+// hash_activity.rs
+
+//! Which hashes are admitted under `--max-hashes`, and how recently each one sent a record.
+//!
+//! When a new hash arrives with the limit reached, `--hash-eviction` decides whether it is
+//! refused or the least recently active hash is evicted to make room for it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::config::HashEviction;
+
+/// Refused hashes remembered at once, so refusal notices can't grow without bound.
+const MAX_REFUSAL_NOTICES: usize = 1000;
+
+/// Outcome of a record for a hash arriving.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// Admitted after evicting the contained hash
+    Evicted(String),
+    /// Refused; `notify` is set the first time since the last `forget_removed` call
+    Refused { notify: bool },
+}
+
+pub struct HashActivity {
+    max_hashes: usize,
+    policy: HashEviction,
+    /// Sequence number of each hash's latest record and when it arrived
+    last_active: HashMap<String, (u64, Instant)>,
+    by_recency: BTreeMap<u64, String>,
+    next_sequence: u64,
+    refused: HashSet<String>,
+}
+
+impl HashActivity {
+    pub fn new(max_hashes: usize, policy: HashEviction) -> Self {
+        HashActivity {
+            max_hashes,
+            policy,
+            last_active: HashMap::new(),
+            by_recency: BTreeMap::new(),
+            next_sequence: 0,
+            refused: HashSet::new(),
+        }
+    }
+
+    /// Track stored hashes as admitted, given from least to most recently active.
+    pub fn load(&mut self, hashes: impl IntoIterator<Item = String>) {
+        let now = Instant::now();
+        for hash in hashes {
+            self.mark_active(hash, now);
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.last_active.contains_key(hash)
+    }
+
+    /// Note a record for `hash` and decide whether it may be stored.
+    pub fn admit(&mut self, hash: &str, now: Instant) -> Admission {
+        if let Some((sequence, active_at)) = self.last_active.get_mut(hash) {
+            let hash = self.by_recency.remove(sequence).expect("hash activity out of sync");
+            *sequence = self.next_sequence;
+            *active_at = now;
+            self.by_recency.insert(self.next_sequence, hash);
+            self.next_sequence += 1;
+            return Admission::Admitted;
+        }
+
+        let mut evicted = None;
+        if self.last_active.len() >= self.max_hashes {
+            if self.policy == HashEviction::Reject || self.by_recency.is_empty() {
+                let notify = self.refused.len() < MAX_REFUSAL_NOTICES && self.refused.insert(hash.to_string());
+                return Admission::Refused { notify };
+            }
+            let (_, least_recent) = self.by_recency.pop_first().expect("checked above");
+            self.last_active.remove(&least_recent);
+            evicted = Some(least_recent);
+        }
+
+        self.mark_active(hash.to_string(), now);
+        evicted.map_or(Admission::Admitted, Admission::Evicted)
+    }
+
+    fn mark_active(&mut self, hash: String, now: Instant) {
+        if let Some((sequence, _)) = self.last_active.remove(&hash) {
+            self.by_recency.remove(&sequence);
+        }
+        self.last_active.insert(hash.clone(), (self.next_sequence, now));
+        self.by_recency.insert(self.next_sequence, hash);
+        self.next_sequence += 1;
+    }
+
+    /// Forget hashes without stored rows (purged or trimmed away) that haven't sent anything
+    /// for `idle`, so they stop counting against the limit. Also lets refused hashes be
+    /// noticed again.
+    pub fn forget_removed(&mut self, stored: &HashSet<String>, idle: Duration, now: Instant) {
+        let by_recency = &mut self.by_recency;
+        self.last_active.retain(|hash, (sequence, active_at)| {
+            let keep = stored.contains(hash) || now.saturating_duration_since(*active_at) < idle;
+            if !keep {
+                by_recency.remove(sequence);
+            }
+            keep
+        });
+        self.refused.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_new_hashes_at_the_limit() {
+        let mut activity = HashActivity::new(2, HashEviction::Reject);
+        let now = Instant::now();
+        assert_eq!(activity.admit("a", now), Admission::Admitted);
+        assert_eq!(activity.admit("b", now), Admission::Admitted);
+        assert_eq!(activity.admit("c", now), Admission::Refused { notify: true });
+        assert_eq!(activity.admit("c", now), Admission::Refused { notify: false });
+        // Known hashes keep going
+        assert_eq!(activity.admit("a", now), Admission::Admitted);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_active_hash() {
+        let mut activity = HashActivity::new(2, HashEviction::Delete);
+        let now = Instant::now();
+        activity.admit("a", now);
+        activity.admit("b", now);
+        activity.admit("a", now);
+        assert_eq!(activity.admit("c", now), Admission::Evicted("b".to_string()));
+        assert!(activity.contains("a") && activity.contains("c") && !activity.contains("b"));
+        assert_eq!(activity.admit("b", now), Admission::Evicted("a".to_string()));
+    }
+
+    #[test]
+    fn loaded_hashes_count_against_the_limit_in_order() {
+        let mut activity = HashActivity::new(2, HashEviction::Archive);
+        activity.load(["old".to_string(), "new".to_string()]);
+        assert_eq!(activity.admit("next", Instant::now()), Admission::Evicted("old".to_string()));
+    }
+
+    #[test]
+    fn forgets_idle_hashes_without_stored_rows() {
+        let mut activity = HashActivity::new(2, HashEviction::Reject);
+        let now = Instant::now();
+        activity.admit("kept", now);
+        activity.admit("purged", now);
+        assert_eq!(activity.admit("new", now), Admission::Refused { notify: true });
+
+        let later = now + Duration::from_secs(60);
+        let stored = HashSet::from(["kept".to_string()]);
+        activity.forget_removed(&stored, Duration::from_secs(30), later);
+        assert!(activity.contains("kept") && !activity.contains("purged"));
+        assert_eq!(activity.admit("new", later), Admission::Admitted);
+    }
+}
@@ -0,0 +1,171 @@
+// levels.rs
+
+//! Normalization of the many ways clients spell a log level.
+//!
+//! Every record is stored with one of the canonical level names below and its severity on an
+//! ascending scale, so filtering and sorting work no matter which client sent it. Names are
+//! matched case-insensitively and common spellings (`WARNING`, `CRITICAL`, ...) are understood.
+//! Numbers 1-7 are read as syslog severities and larger ones as Python logging levels, while 0
+//! is Python's `NOTSET` and ranked like `INFO`.
+//! Anything else keeps its name and is ranked like `INFO`.
+
+use serde::{Deserialize, Deserializer};
+
+/// Canonical level names, indexed by severity.
+pub const LEVELS: [&str; 7] = ["TRACE", "DEBUG", "INFO", "NOTICE", "WARN", "ERROR", "FATAL"];
+
+/// Severity given to levels that cannot be recognized.
+pub const DEFAULT_SEVERITY: i64 = 2;
+
+/// Spellings used by other logging libraries and protocols.
+const BUILTIN_ALIASES: &[(&str, &str)] = &[
+    ("VERBOSE", "TRACE"),
+    ("FINEST", "TRACE"),
+    ("DBG", "DEBUG"),
+    ("FINE", "DEBUG"),
+    ("INFORMATION", "INFO"),
+    ("INFORMATIONAL", "INFO"),
+    ("WARNING", "WARN"),
+    ("ERR", "ERROR"),
+    ("SEVERE", "ERROR"),
+    ("CRIT", "FATAL"),
+    ("CRITICAL", "FATAL"),
+    ("ALERT", "FATAL"),
+    ("EMERG", "FATAL"),
+    ("EMERGENCY", "FATAL"),
+    ("PANIC", "FATAL"),
+];
+
+/// Severity of a level name or number, without any user-defined aliases.
+fn builtin_severity(level: &str) -> Option<i64> {
+    if let Ok(number) = level.parse::<i64>() {
+        return Some(match number {
+            // Syslog's emergency, but also Python's NOTSET, which says nothing about severity
+            0 => DEFAULT_SEVERITY,
+            1..=7 => syslog_severity(number as u8),
+            8..=19 => 1,
+            20..=29 => 2,
+            30..=39 => 4,
+            40..=49 => 5,
+            50.. => 6,
+            _ => return None,
+        });
+    }
+
+    let name = BUILTIN_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == level)
+        .map_or(level, |(_, name)| *name);
+    LEVELS.iter().position(|canonical| *canonical == name).map(|severity| severity as i64)
+}
+
+/// Severity of a syslog severity number (0 = emergency, 7 = debug).
+fn syslog_severity(severity: u8) -> i64 {
+    match severity {
+        0..=2 => 6,
+        3 => 5,
+        4 => 4,
+        5 => 3,
+        6 => 2,
+        _ => 1,
+    }
+}
+
+/// Canonical level name of a syslog severity (0 = emergency, 7 = debug).
+pub fn syslog_level(severity: u8) -> &'static str {
+    LEVELS[syslog_severity(severity) as usize]
+}
+
+/// Normalize `level` into its canonical name and severity.
+///
+/// `aliases` map custom names onto a level and take precedence over the built-in spellings.
+pub fn normalize(level: &str, aliases: &[(String, String)]) -> (String, i64) {
+    let level = level.trim().to_uppercase();
+    let resolved = aliases
+        .iter()
+        .find(|(alias, _)| *alias == level)
+        .map_or(level.as_str(), |(_, target)| target.as_str());
+
+    match builtin_severity(resolved) {
+        Some(severity) => (LEVELS[severity as usize].to_string(), severity),
+        None if level.is_empty() => (LEVELS[DEFAULT_SEVERITY as usize].to_string(), DEFAULT_SEVERITY),
+        None => (level, DEFAULT_SEVERITY),
+    }
+}
+
+/// Parse a `NAME=LEVEL` alias from the command line.
+pub fn parse_alias(alias: &str) -> Result<(String, String), String> {
+    let (name, target) = alias
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=LEVEL, got `{}`", alias))?;
+    let name = name.trim().to_uppercase();
+    let target = target.trim().to_uppercase();
+    if name.is_empty() {
+        return Err(format!("missing alias name in `{}`", alias));
+    }
+    if builtin_severity(&target).is_none() {
+        return Err(format!("unknown level `{}`, expected one of {}", target, LEVELS.join(", ")));
+    }
+    Ok((name, target))
+}
+
+/// Accept a level sent either as a string or as a number.
+pub fn deserialize_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Level {
+        Name(String),
+        Number(i64),
+    }
+
+    Ok(match Level::deserialize(deserializer)? {
+        Level::Name(name) => name,
+        Level::Number(number) => number.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_syslog_and_python_numbers() {
+        assert_eq!(normalize("3", &[]), ("ERROR".to_string(), 5));
+        assert_eq!(normalize("7", &[]), ("DEBUG".to_string(), 1));
+        assert_eq!(normalize("10", &[]), ("DEBUG".to_string(), 1));
+        assert_eq!(normalize("50", &[]), ("FATAL".to_string(), 6));
+        // Python's NOTSET
+        assert_eq!(normalize("0", &[]), ("INFO".to_string(), DEFAULT_SEVERITY));
+        assert_eq!(syslog_level(0), "FATAL");
+    }
+
+    #[test]
+    fn understands_other_spellings() {
+        assert_eq!(normalize(" warning ", &[]), ("WARN".to_string(), 4));
+        assert_eq!(normalize("Critical", &[]), ("FATAL".to_string(), 6));
+        assert_eq!(normalize("finest", &[]), ("TRACE".to_string(), 0));
+        assert_eq!(normalize("err", &[]), ("ERROR".to_string(), 5));
+        assert_eq!(normalize("", &[]), ("INFO".to_string(), DEFAULT_SEVERITY));
+        assert_eq!(normalize("custom", &[]), ("CUSTOM".to_string(), DEFAULT_SEVERITY));
+    }
+
+    #[test]
+    fn aliases_take_precedence() {
+        let aliases = vec![
+            parse_alias("oops=error").unwrap(),
+            parse_alias("FINE = info").unwrap(),
+        ];
+        assert_eq!(normalize("OOPS", &aliases), ("ERROR".to_string(), 5));
+        assert_eq!(normalize("fine", &aliases), ("INFO".to_string(), 2));
+        assert_eq!(normalize("dbg", &aliases), ("DEBUG".to_string(), 1));
+    }
+
+    #[test]
+    fn parses_aliases() {
+        assert_eq!(parse_alias("hint=notice"), Ok(("HINT".to_string(), "NOTICE".to_string())));
+        assert_eq!(parse_alias("loud=critical"), Ok(("LOUD".to_string(), "CRITICAL".to_string())));
+        assert!(parse_alias("no-equals").is_err());
+        assert!(parse_alias("=warn").is_err());
+        assert!(parse_alias("x=bogus").is_err());
+    }
+}
@@ -1,9 +1,28 @@
 use log::{Metadata, Record};
 use serde::Serialize;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use chrono::Utc;
+use protocol::{
+    encode_json_batch, encode_msgpack_batch, frame_msgpack, ClientHello, HelloFrame, ServerHello, CAP_BATCH,
+    CAP_MSGPACK, MAX_FRAME_LENGTH, PROTOCOL_VERSION,
+};
+
+/// How long `TcpLogger::handshake` waits for the server to answer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long records are held back to be coalesced into one frame once batching is granted.
+pub const BATCH_WINDOW: Duration = Duration::from_millis(10);
+
+/// Pending records that trigger an immediate flush instead of waiting for the window.
+pub const MAX_BATCH_RECORDS: usize = 500;
+
+/// Bytes a batch frame adds around its records: the brackets and newline of a JSON array, or
+/// the array header of a MessagePack one.
+const BATCH_FRAME_OVERHEAD: usize = 5;
 
 #[derive(Serialize)]
 struct LogMessage<'a> {
@@ -21,6 +40,30 @@ pub struct TcpLogger {
     server_addr: String,
     hash: String,
     stream: Arc<Mutex<TcpStream>>,
+    server_hello: Mutex<Option<ServerHello>>,
+    /// Encoded records waiting for the next batch flush.
+    pending: Arc<Mutex<PendingBatch>>,
+}
+
+/// Encoded records waiting to be sent as one batch frame.
+#[derive(Default)]
+struct PendingBatch {
+    records: Vec<Vec<u8>>,
+    /// Size of the frame the records would make, not counting the framing overhead
+    frame_length: usize,
+}
+
+impl PendingBatch {
+    /// Whether `record` still fits into a frame of `max_frame_length` bytes.
+    fn fits(&self, record: &[u8], max_frame_length: usize) -> bool {
+        self.frame_length + record.len() + 1 + BATCH_FRAME_OVERHEAD <= max_frame_length
+    }
+
+    fn push(&mut self, record: Vec<u8>) {
+        // Each record takes a separating comma in a JSON batch
+        self.frame_length += record.len() + 1;
+        self.records.push(record);
+    }
 }
 
 impl TcpLogger {
@@ -30,8 +73,16 @@ impl TcpLogger {
             server_addr: server_addr.to_string(),
             hash: hash.to_string(),
             stream: Arc::new(Mutex::new(stream)),
+            server_hello: Mutex::new(None),
+            pending: Arc::new(Mutex::new(PendingBatch::default())),
         };
-        log::set_boxed_logger(Box::new(logger))?;
+        logger.install(level)
+    }
+
+    /// Register an already connected logger, e.g. one that completed a `handshake`,
+    /// as the global `log` backend.
+    pub fn install(self, level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
         log::set_max_level(level);
         Ok(())
     }
@@ -42,9 +93,61 @@ impl TcpLogger {
             server_addr: server_addr.to_string(),
             hash: hash.to_string(),
             stream: Arc::new(Mutex::new(stream)),
+            server_hello: Mutex::new(None),
+            pending: Arc::new(Mutex::new(PendingBatch::default())),
         })
     }
 
+    /// Send a hello frame declaring our protocol version and the requested capabilities,
+    /// then wait for the server to answer with its version, limits and granted capabilities.
+    ///
+    /// The hello must be the first thing sent on the connection, so call this before logging.
+    /// Servers that predate the handshake ignore the hello and never answer, in which case
+    /// this returns a timeout error and the connection can still be used for logging.
+    /// Requesting [`protocol::CAP_MSGPACK`] switches later records to binary frames, and
+    /// [`protocol::CAP_BATCH`] coalesces records sent within [`BATCH_WINDOW`] into one frame.
+    pub fn handshake(&self, capabilities: &[&str]) -> Result<ServerHello, std::io::Error> {
+        let hello = HelloFrame {
+            hello: ClientHello {
+                protocol_version: PROTOCOL_VERSION,
+                client: "ironlog-rust".to_string(),
+                client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                hash: Some(self.hash.clone()),
+                capabilities: capabilities.iter().map(|cap| cap.to_string()).collect(),
+            },
+        };
+        let json = serde_json::to_string(&hello)?;
+
+        let stream = self.stream.lock().unwrap();
+        writeln!(&*stream, "{}", json)?;
+
+        let reader_stream = stream.try_clone()?;
+        reader_stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reply = String::new();
+        let result = BufReader::new(&reader_stream).read_line(&mut reply);
+        reader_stream.set_read_timeout(None)?;
+        result?;
+
+        let reply: HelloFrame<ServerHello> = serde_json::from_str(&reply)?;
+        drop(stream);
+
+        let already_batching = self
+            .server_hello
+            .lock()
+            .unwrap()
+            .replace(reply.hello.clone())
+            .is_some_and(|hello| hello.has_capability(CAP_BATCH));
+        if reply.hello.has_capability(CAP_BATCH) && !already_batching {
+            self.spawn_batch_flusher(reply.hello.has_capability(CAP_MSGPACK));
+        }
+        Ok(reply.hello)
+    }
+
+    /// The server's answer to the last successful `handshake`, if any.
+    pub fn server_hello(&self) -> Option<ServerHello> {
+        self.server_hello.lock().unwrap().clone()
+    }
+
     pub fn info(&self, message: &str) {
         self.log_message(log::Level::Info, message);
     }
@@ -73,13 +176,102 @@ impl TcpLogger {
             hash: self.hash.clone(),
         };
 
-        if let Ok(json) = serde_json::to_string(&log_message) {
-            let mut stream = self.stream.lock().unwrap();
-            if let Err(e) = writeln!(stream, "{}", json) {
-                eprintln!("Failed to send log: {}", e);
+        self.send(&log_message);
+    }
+
+    /// Write one record using the framing agreed on in the handshake.
+    fn send(&self, log_message: &LogMessage) {
+        let (msgpack, batch, max_frame_length) = match self.server_hello.lock().unwrap().as_ref() {
+            Some(hello) => (
+                hello.has_capability(CAP_MSGPACK),
+                hello.has_capability(CAP_BATCH),
+                hello.max_frame_length.unwrap_or(MAX_FRAME_LENGTH),
+            ),
+            None => (false, false, MAX_FRAME_LENGTH),
+        };
+
+        let record = if msgpack {
+            rmp_serde::to_vec_named(log_message).ok()
+        } else {
+            serde_json::to_vec(log_message).ok()
+        };
+        let Some(mut record) = record else { return };
+
+        if batch {
+            let mut pending = self.pending.lock().unwrap();
+            // Send what is pending first if this record would take the frame past the limit
+            while !pending.records.is_empty() && !pending.fits(&record, max_frame_length) {
+                write_batch(&self.stream, pending, msgpack);
+                pending = self.pending.lock().unwrap();
+            }
+            pending.push(record);
+            if pending.records.len() >= MAX_BATCH_RECORDS {
+                write_batch(&self.stream, pending, msgpack);
             }
+            return;
+        }
+
+        let frame = if msgpack {
+            frame_msgpack(&record)
+        } else {
+            record.push(b'\n');
+            record
+        };
+        let mut stream = self.stream.lock().unwrap();
+        if let Err(e) = stream.write_all(&frame) {
+            eprintln!("Failed to send log: {}", e);
         }
     }
+
+    /// Send any records still waiting for the batch window.
+    fn flush_pending(&self) {
+        let msgpack = self
+            .server_hello
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|hello| hello.has_capability(CAP_MSGPACK));
+        write_batch(&self.stream, self.pending.lock().unwrap(), msgpack);
+    }
+
+    fn spawn_batch_flusher(&self, msgpack: bool) {
+        // The flusher stops once the logger, and with it the last strong `pending`, is gone
+        let pending = Arc::downgrade(&self.pending);
+        let stream = Arc::clone(&self.stream);
+        thread::spawn(move || loop {
+            thread::sleep(BATCH_WINDOW);
+            let Some(pending) = pending.upgrade() else { break };
+            write_batch(&stream, pending.lock().unwrap(), msgpack);
+        });
+    }
+}
+
+/// Write out and clear the pending records as one frame.
+///
+/// The stream lock is taken before the pending lock is released so that batches reach the
+/// server in the order their records were logged.
+fn write_batch(stream: &Mutex<TcpStream>, mut pending: std::sync::MutexGuard<'_, PendingBatch>, msgpack: bool) {
+    if pending.records.is_empty() {
+        return;
+    }
+    let frame = if msgpack {
+        encode_msgpack_batch(&pending.records)
+    } else {
+        encode_json_batch(&pending.records)
+    };
+    *pending = PendingBatch::default();
+
+    let mut stream = stream.lock().unwrap();
+    drop(pending);
+    if let Err(e) = stream.write_all(&frame) {
+        eprintln!("Failed to send logs: {}", e);
+    }
+}
+
+impl Drop for TcpLogger {
+    fn drop(&mut self) {
+        self.flush_pending();
+    }
 }
 
 impl log::Log for TcpLogger {
@@ -99,18 +291,33 @@ impl log::Log for TcpLogger {
                 line: record.line(),
                 hash: self.hash.clone(),
             };
-            if let Ok(json) = serde_json::to_string(&log_message) {
-                let mut stream = self.stream.lock().unwrap();
-                if let Err(e) = writeln!(stream, "{}", json) {
-                    eprintln!("Failed to send log: {}", e);
-                }
-            }
+            self.send(&log_message);
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        self.flush_pending();
+    }
 }
 
 pub mod config;
 pub mod client_handler;
-pub mod types;
\ No newline at end of file
+pub mod types;
+pub mod testing;
+pub mod protocol;
+pub mod syslog;
+pub mod gelf;
+pub mod otlp;
+pub mod levels;
+pub mod forward;
+pub mod loki;
+pub mod elasticsearch;
+pub mod access;
+pub mod shutdown;
+pub mod writer;
+pub mod rate_limit;
+pub mod hash_activity;
+pub mod retention;
+pub mod retention_rules;
+pub mod sweep;
+pub mod schema;
\ No newline at end of file
@@ -0,0 +1,332 @@
+// loki.rs
+
+//! Loki-compatible push API, for Promtail, Grafana Agent and Docker's Loki driver.
+//!
+//! Push requests arrive either as snappy-compressed protobuf or as JSON. Both are turned into
+//! the same `Stream`s before being mapped into `LogMessage`:
+//!
+//! - the first label from `--loki-hash-labels` present on the stream → hash
+//! - `level`, `detected_level` or `severity` label or metadata → level
+//! - line → message
+//! - the remaining labels and structured metadata → fields
+
+use std::io::Read;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use prost::Message;
+use serde_json::Value;
+use sqlx::types::Json;
+
+use crate::types::{Fields, LogMessage};
+
+/// Largest (decompressed) push request accepted.
+pub const MAX_LOKI_REQUEST: usize = 8 * 1024 * 1024;
+
+/// Label or metadata keys that may carry the level, in order of preference.
+const LEVEL_KEYS: [&str; 3] = ["level", "detected_level", "severity"];
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamAdapter {
+    /// Labels in Prometheus notation, e.g. `{app="api", env="prod"}`.
+    #[prost(string, tag = "1")]
+    pub labels: String,
+    #[prost(message, repeated, tag = "2")]
+    pub entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub line: String,
+    #[prost(message, repeated, tag = "3")]
+    pub structured_metadata: Vec<LabelPairAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPairAdapter {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+/// One stream of a push request with its labels already parsed.
+#[derive(Debug, Clone)]
+pub struct Stream {
+    pub labels: Fields,
+    pub entries: Vec<EntryAdapter>,
+}
+
+/// Decode a snappy-compressed protobuf push request.
+pub fn decode_protobuf(body: &[u8]) -> Option<Vec<Stream>> {
+    let length = snap::raw::decompress_len(body).ok()?;
+    if length > MAX_LOKI_REQUEST {
+        return None;
+    }
+    let body = snap::raw::Decoder::new().decompress_vec(body).ok()?;
+    let request = PushRequest::decode(body.as_slice()).ok()?;
+
+    request
+        .streams
+        .into_iter()
+        .map(|stream| {
+            Some(Stream {
+                labels: parse_labels(&stream.labels)?,
+                entries: stream.entries,
+            })
+        })
+        .collect()
+}
+
+/// Decode a JSON push request, optionally gzip compressed:
+/// `{"streams":[{"stream":{"app":"api"},"values":[["<unix nanos>","line",{"metadata":"value"}]]}]}`.
+pub fn decode_json(body: &[u8]) -> Option<Vec<Stream>> {
+    let body = if body.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body)
+            .take(MAX_LOKI_REQUEST as u64 + 1)
+            .read_to_end(&mut decoded)
+            .ok()?;
+        if decoded.len() > MAX_LOKI_REQUEST {
+            return None;
+        }
+        decoded
+    } else {
+        body.to_vec()
+    };
+
+    let request: Value = serde_json::from_slice(&body).ok()?;
+    request
+        .get("streams")?
+        .as_array()?
+        .iter()
+        .map(|stream| {
+            let labels = match stream.get("stream") {
+                Some(Value::Object(labels)) => labels.clone(),
+                _ => Fields::new(),
+            };
+            let entries = stream
+                .get("values")?
+                .as_array()?
+                .iter()
+                .map(json_entry)
+                .collect::<Option<Vec<_>>>()?;
+            Some(Stream { labels, entries })
+        })
+        .collect()
+}
+
+fn json_entry(value: &Value) -> Option<EntryAdapter> {
+    let value = value.as_array()?;
+    let nanos: i64 = value.first()?.as_str()?.parse().ok()?;
+    let line = value.get(1)?.as_str()?.to_string();
+    let structured_metadata = match value.get(2) {
+        Some(Value::Object(metadata)) => metadata
+            .iter()
+            .map(|(name, value)| LabelPairAdapter {
+                name: name.clone(),
+                value: value.as_str().map_or_else(|| value.to_string(), str::to_string),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Some(EntryAdapter {
+        timestamp: Some(Timestamp {
+            seconds: nanos.div_euclid(1_000_000_000),
+            nanos: nanos.rem_euclid(1_000_000_000) as i32,
+        }),
+        line,
+        structured_metadata,
+    })
+}
+
+/// Parse Prometheus-style labels, `{name="value", ...}`.
+pub fn parse_labels(labels: &str) -> Option<Fields> {
+    let mut fields = Fields::new();
+    let mut rest = labels.trim().strip_prefix('{')?.strip_suffix('}')?;
+
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if rest.is_empty() {
+            return Some(fields);
+        }
+        let (name, after) = rest.split_once("=\"")?;
+
+        let mut value = String::new();
+        let mut chars = after.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()? {
+                    (_, 'n') => value.push('\n'),
+                    (_, c) => value.push(c),
+                },
+                (index, '"') => break index,
+                (_, c) => value.push(c),
+            }
+        };
+        fields.insert(name.trim().to_string(), Value::String(value));
+        rest = &after[end + 1..];
+    }
+}
+
+/// Flatten push request streams into log messages.
+///
+/// The hash is the value of the first label in `hash_labels` found on the stream, or the full
+/// label set when none is.
+pub fn into_log_messages(streams: Vec<Stream>, hash_labels: &[String]) -> Vec<LogMessage> {
+    let mut log_messages = Vec::new();
+
+    for stream in streams {
+        let hash = hash_labels
+            .iter()
+            .find_map(|label| stream.labels.get(label).and_then(Value::as_str))
+            .filter(|hash| !hash.is_empty())
+            .map_or_else(|| Value::Object(stream.labels.clone()).to_string(), str::to_string);
+
+        for entry in stream.entries {
+            let mut fields = stream.labels.clone();
+            for pair in entry.structured_metadata {
+                fields.insert(pair.name, Value::String(pair.value));
+            }
+            let level = match LEVEL_KEYS.iter().find_map(|key| fields.remove(*key)) {
+                Some(Value::String(level)) => level,
+                _ => "INFO".to_string(),
+            };
+            let timestamp = entry
+                .timestamp
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp.seconds, timestamp.nanos.max(0) as u32))
+                .unwrap_or_else(Utc::now);
+
+            log_messages.push(LogMessage {
+                level,
+                severity: None,
+                message: entry.line,
+                target: "loki".to_string(),
+                module_path: None,
+                file: None,
+                line: None,
+                hash: hash.clone(),
+                timestamp: timestamp.to_rfc3339(),
+                fields: (!fields.is_empty()).then_some(Json(fields)),
+                peer: None,
+                session_id: None,
+            });
+        }
+    }
+
+    log_messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn hash_labels() -> Vec<String> {
+        vec!["host".to_string(), "job".to_string()]
+    }
+
+    #[test]
+    fn parses_labels() {
+        let labels = parse_labels(r#"{app="api", env="prod",job="web"}"#).unwrap();
+        assert_eq!(Value::Object(labels), json!({ "app": "api", "env": "prod", "job": "web" }));
+        assert_eq!(parse_labels(" {} ").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn unescapes_quoted_label_values() {
+        let labels = parse_labels(r#"{msg="say \"hi\", then \\ and\nmore", path="a=b,c"}"#).unwrap();
+        assert_eq!(labels["msg"], json!("say \"hi\", then \\ and\nmore"));
+        assert_eq!(labels["path"], json!("a=b,c"));
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        assert!(parse_labels(r#"app="api""#).is_none());
+        assert!(parse_labels(r#"{app="api}"#).is_none());
+        assert!(parse_labels(r#"{app=api}"#).is_none());
+    }
+
+    #[test]
+    fn decodes_snappy_protobuf() {
+        let request = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: r#"{job="promtail", level="warn"}"#.to_string(),
+                entries: vec![EntryAdapter {
+                    timestamp: Some(Timestamp { seconds: 1_700_000_000, nanos: 5 }),
+                    line: "disk almost full".to_string(),
+                    structured_metadata: vec![LabelPairAdapter {
+                        name: "trace_id".to_string(),
+                        value: "abc".to_string(),
+                    }],
+                }],
+            }],
+        };
+        let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec()).unwrap();
+
+        let log_messages = into_log_messages(decode_protobuf(&body).unwrap(), &hash_labels());
+        assert_eq!(log_messages.len(), 1);
+        let log = &log_messages[0];
+        assert_eq!(log.hash, "promtail");
+        assert_eq!(log.level, "warn");
+        assert_eq!(log.message, "disk almost full");
+        assert_eq!(log.timestamp, "2023-11-14T22:13:20.000000005+00:00");
+        let fields = Value::Object(log.fields.as_ref().unwrap().0.clone());
+        assert_eq!(fields, json!({ "job": "promtail", "trace_id": "abc" }));
+
+        assert!(decode_protobuf(b"not snappy").is_none());
+    }
+
+    #[test]
+    fn decodes_json_with_metadata_and_gzip() {
+        let body = br#"{"streams": [
+            {"stream": {"host": "db-1"}, "values": [
+                ["1700000000000000000", "plain"],
+                ["1700000001000000000", "with metadata", {"detected_level": "error", "user": "7"}]
+            ]}
+        ]}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+
+        for body in [body.to_vec(), encoder.finish().unwrap()] {
+            let log_messages = into_log_messages(decode_json(&body).unwrap(), &hash_labels());
+            assert_eq!(log_messages.len(), 2);
+            assert_eq!(log_messages[0].level, "INFO");
+            assert_eq!(log_messages[0].timestamp, "2023-11-14T22:13:20+00:00");
+            assert_eq!(log_messages[1].hash, "db-1");
+            assert_eq!(log_messages[1].level, "error");
+            assert_eq!(log_messages[1].fields.as_ref().unwrap().0["user"], json!("7"));
+        }
+
+        assert!(decode_json(br#"{"streams": [{"values": [[1700000000, "number timestamp"]]}]}"#).is_none());
+    }
+
+    #[test]
+    fn hashes_by_all_labels_without_a_hash_label() {
+        let streams = vec![Stream {
+            labels: parse_labels(r#"{app="api"}"#).unwrap(),
+            entries: vec![EntryAdapter::default()],
+        }];
+        assert_eq!(into_log_messages(streams, &hash_labels())[0].hash, r#"{"app":"api"}"#);
+    }
+}
@@ -0,0 +1,501 @@
+// otlp.rs
+
+//! OpenTelemetry OTLP/HTTP logs receiver.
+//!
+//! Export requests arrive either protobuf or JSON encoded. JSON requests are converted into
+//! the same message types as protobuf ones so both share one mapping into `LogMessage`:
+//!
+//! - `service.name` resource attribute → hash
+//! - severity number (or text) → level
+//! - body → message
+//! - scope name → target
+//! - trace/span ids, severity text, record attributes and the remaining resource
+//!   attributes → fields
+
+use std::io::Read;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use prost::Message;
+use serde_json::{json, Value};
+use sqlx::types::Json;
+
+use crate::types::{Fields, LogMessage};
+
+/// Largest (decompressed) export request accepted.
+pub const MAX_OTLP_REQUEST: usize = 8 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsServiceResponse {
+    #[prost(message, optional, tag = "1")]
+    pub partial_success: Option<ExportLogsPartialSuccess>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsPartialSuccess {
+    #[prost(int64, tag = "1")]
+    pub rejected_log_records: i64,
+    #[prost(string, tag = "2")]
+    pub error_message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    pub observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    pub severity_number: i32,
+    #[prost(string, tag = "3")]
+    pub severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    pub body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(bytes = "vec", tag = "9")]
+    pub trace_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "10")]
+    pub span_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub value: Option<any_value::Value>,
+}
+
+pub mod any_value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        StringValue(String),
+        #[prost(bool, tag = "2")]
+        BoolValue(bool),
+        #[prost(int64, tag = "3")]
+        IntValue(i64),
+        #[prost(double, tag = "4")]
+        DoubleValue(f64),
+        #[prost(message, tag = "5")]
+        ArrayValue(super::ArrayValue),
+        #[prost(message, tag = "6")]
+        KvlistValue(super::KeyValueList),
+        #[prost(bytes = "vec", tag = "7")]
+        BytesValue(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+/// Undo gzip `Content-Encoding` if present.
+pub fn decompress(body: &[u8]) -> Option<Vec<u8>> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Some(body.to_vec());
+    }
+    let mut decoded = Vec::new();
+    GzDecoder::new(body)
+        .take(MAX_OTLP_REQUEST as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= MAX_OTLP_REQUEST).then_some(decoded)
+}
+
+pub fn decode_protobuf(body: &[u8]) -> Option<ExportLogsServiceRequest> {
+    ExportLogsServiceRequest::decode(body).ok()
+}
+
+/// Decode an OTLP/JSON export request (camelCase keys, 64-bit integers as strings,
+/// trace and span ids as hex).
+pub fn decode_json(body: &[u8]) -> Option<ExportLogsServiceRequest> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    let resource_logs = array(&request, "resourceLogs")
+        .iter()
+        .map(|resource_logs| ResourceLogs {
+            resource: resource_logs.get("resource").map(|resource| Resource {
+                attributes: json_attributes(resource),
+            }),
+            scope_logs: array(resource_logs, "scopeLogs")
+                .iter()
+                .map(|scope_logs| ScopeLogs {
+                    scope: scope_logs.get("scope").map(|scope| InstrumentationScope {
+                        name: string(scope, "name"),
+                        version: string(scope, "version"),
+                    }),
+                    log_records: array(scope_logs, "logRecords").iter().map(json_log_record).collect(),
+                })
+                .collect(),
+        })
+        .collect();
+    Some(ExportLogsServiceRequest { resource_logs })
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value.get(key).and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default()
+}
+
+fn string(value: &Value, key: &str) -> String {
+    value.get(key).and_then(Value::as_str).unwrap_or_default().to_string()
+}
+
+/// 64-bit integers may be encoded as JSON numbers or decimal strings.
+fn integer(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn hex_bytes(value: &Value, key: &str) -> Vec<u8> {
+    let hex = value.get(key).and_then(Value::as_str).unwrap_or_default();
+    (0..hex.len() / 2)
+        .map_while(|index| u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+fn json_attributes(value: &Value) -> Vec<KeyValue> {
+    let values = value.get("attributes").or_else(|| value.get("values"));
+    values
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|key_value| KeyValue {
+            key: string(key_value, "key"),
+            value: key_value.get("value").map(json_any_value),
+        })
+        .collect()
+}
+
+fn json_any_value(value: &Value) -> AnyValue {
+    use any_value::Value as V;
+
+    let value = if let Some(string) = value.get("stringValue").and_then(Value::as_str) {
+        Some(V::StringValue(string.to_string()))
+    } else if let Some(boolean) = value.get("boolValue").and_then(Value::as_bool) {
+        Some(V::BoolValue(boolean))
+    } else if let Some(int) = value.get("intValue").and_then(integer) {
+        Some(V::IntValue(int))
+    } else if let Some(double) = value.get("doubleValue").and_then(Value::as_f64) {
+        Some(V::DoubleValue(double))
+    } else if let Some(array_value) = value.get("arrayValue") {
+        Some(V::ArrayValue(ArrayValue {
+            values: array(array_value, "values").iter().map(json_any_value).collect(),
+        }))
+    } else if let Some(kvlist) = value.get("kvlistValue") {
+        Some(V::KvlistValue(KeyValueList {
+            values: json_attributes(kvlist),
+        }))
+    } else {
+        // Bytes are base64 in OTLP/JSON; keep the encoded text rather than guess at the content
+        value
+            .get("bytesValue")
+            .and_then(Value::as_str)
+            .map(|bytes| V::StringValue(bytes.to_string()))
+    };
+    AnyValue { value }
+}
+
+fn json_log_record(record: &Value) -> LogRecord {
+    let nanos = |key| record.get(key).and_then(integer).unwrap_or(0) as u64;
+    LogRecord {
+        time_unix_nano: nanos("timeUnixNano"),
+        observed_time_unix_nano: nanos("observedTimeUnixNano"),
+        severity_number: record.get("severityNumber").and_then(integer).unwrap_or(0) as i32,
+        severity_text: string(record, "severityText"),
+        body: record.get("body").map(json_any_value),
+        attributes: json_attributes(record),
+        trace_id: hex_bytes(record, "traceId"),
+        span_id: hex_bytes(record, "spanId"),
+    }
+}
+
+fn to_json(value: &AnyValue) -> Value {
+    use any_value::Value as V;
+
+    match &value.value {
+        Some(V::StringValue(string)) => json!(string),
+        Some(V::BoolValue(boolean)) => json!(boolean),
+        Some(V::IntValue(int)) => json!(int),
+        Some(V::DoubleValue(double)) => json!(double),
+        Some(V::ArrayValue(array)) => Value::Array(array.values.iter().map(to_json).collect()),
+        Some(V::KvlistValue(kvlist)) => Value::Object(attributes_to_fields(&kvlist.values)),
+        Some(V::BytesValue(bytes)) => json!(to_hex(bytes)),
+        None => Value::Null,
+    }
+}
+
+fn attributes_to_fields(attributes: &[KeyValue]) -> Fields {
+    attributes
+        .iter()
+        .map(|key_value| {
+            let value = key_value.value.as_ref().map(to_json).unwrap_or(Value::Null);
+            (key_value.key.clone(), value)
+        })
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Map an OTel severity number (1-24) or, failing that, the severity text onto a level.
+pub fn severity_level(severity_number: i32, severity_text: &str) -> String {
+    match severity_number {
+        1..=4 => "TRACE".to_string(),
+        5..=8 => "DEBUG".to_string(),
+        9..=12 => "INFO".to_string(),
+        13..=16 => "WARN".to_string(),
+        17..=20 => "ERROR".to_string(),
+        21..=24 => "FATAL".to_string(),
+        _ if !severity_text.is_empty() => severity_text.to_string(),
+        _ => "INFO".to_string(),
+    }
+}
+
+fn timestamp(unix_nanos: u64) -> Option<String> {
+    (unix_nanos != 0).then(|| DateTime::<Utc>::from_timestamp_nanos(unix_nanos as i64).to_rfc3339())
+}
+
+/// Flatten an export request into log messages.
+pub fn into_log_messages(request: ExportLogsServiceRequest) -> Vec<LogMessage> {
+    let mut log_messages = Vec::new();
+
+    for resource_logs in request.resource_logs {
+        let mut resource = resource_logs
+            .resource
+            .map(|resource| attributes_to_fields(&resource.attributes))
+            .unwrap_or_default();
+        let hash = match resource.remove("service.name") {
+            Some(Value::String(service_name)) if !service_name.is_empty() => service_name,
+            _ => "unknown_service".to_string(),
+        };
+
+        for scope_logs in resource_logs.scope_logs {
+            let target = scope_logs
+                .scope
+                .map(|scope| scope.name)
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "otel".to_string());
+
+            for record in scope_logs.log_records {
+                let message = match record.body.as_ref().map(to_json) {
+                    Some(Value::String(body)) => body,
+                    Some(Value::Null) | None => String::new(),
+                    Some(body) => body.to_string(),
+                };
+
+                let mut fields = attributes_to_fields(&record.attributes);
+                if !record.trace_id.is_empty() {
+                    fields.insert("trace_id".to_string(), json!(to_hex(&record.trace_id)));
+                }
+                if !record.span_id.is_empty() {
+                    fields.insert("span_id".to_string(), json!(to_hex(&record.span_id)));
+                }
+                if !record.severity_text.is_empty() {
+                    fields.insert("severity_text".to_string(), json!(record.severity_text));
+                }
+                if !resource.is_empty() {
+                    fields.insert("resource".to_string(), Value::Object(resource.clone()));
+                }
+
+                log_messages.push(LogMessage {
+                    level: severity_level(record.severity_number, &record.severity_text),
+                    severity: None,
+                    message,
+                    target: target.clone(),
+                    module_path: None,
+                    file: None,
+                    line: None,
+                    hash: hash.clone(),
+                    timestamp: timestamp(record.time_unix_nano)
+                        .or_else(|| timestamp(record.observed_time_unix_nano))
+                        .unwrap_or_else(|| Utc::now().to_rfc3339()),
+                    fields: (!fields.is_empty()).then_some(Json(fields)),
+                    peer: None,
+                    session_id: None,
+                });
+            }
+        }
+    }
+
+    log_messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_value(value: &str) -> Option<AnyValue> {
+        Some(AnyValue { value: Some(any_value::Value::StringValue(value.to_string())) })
+    }
+
+    fn attribute(key: &str, value: Option<AnyValue>) -> KeyValue {
+        KeyValue { key: key.to_string(), value }
+    }
+
+    #[test]
+    fn maps_severity_numbers_then_text() {
+        assert_eq!(severity_level(1, ""), "TRACE");
+        assert_eq!(severity_level(9, "whatever"), "INFO");
+        assert_eq!(severity_level(13, ""), "WARN");
+        assert_eq!(severity_level(17, ""), "ERROR");
+        assert_eq!(severity_level(24, ""), "FATAL");
+        assert_eq!(severity_level(0, "Notice"), "Notice");
+        assert_eq!(severity_level(0, ""), "INFO");
+    }
+
+    #[test]
+    fn maps_protobuf_records() {
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes: vec![
+                        attribute("service.name", string_value("checkout")),
+                        attribute("host.name", string_value("web-1")),
+                    ],
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope { name: "orders".to_string(), version: String::new() }),
+                    log_records: vec![LogRecord {
+                        time_unix_nano: 1_700_000_000_123_456_789,
+                        severity_number: 17,
+                        severity_text: "Error".to_string(),
+                        body: string_value("payment failed"),
+                        attributes: vec![attribute(
+                            "attempt",
+                            Some(AnyValue { value: Some(any_value::Value::IntValue(3)) }),
+                        )],
+                        trace_id: vec![0xab, 0x01],
+                        span_id: vec![0x0f],
+                        ..Default::default()
+                    }],
+                }],
+            }],
+        };
+        let encoded = request.encode_to_vec();
+
+        let log_messages = into_log_messages(decode_protobuf(&encoded).unwrap());
+        assert_eq!(log_messages.len(), 1);
+        let log = &log_messages[0];
+        assert_eq!(log.hash, "checkout");
+        assert_eq!(log.level, "ERROR");
+        assert_eq!(log.message, "payment failed");
+        assert_eq!(log.target, "orders");
+        assert_eq!(log.timestamp, "2023-11-14T22:13:20.123456789+00:00");
+        let fields = &log.fields.as_ref().unwrap().0;
+        assert_eq!(fields["attempt"], json!(3));
+        assert_eq!(fields["trace_id"], json!("ab01"));
+        assert_eq!(fields["span_id"], json!("0f"));
+        assert_eq!(fields["severity_text"], json!("Error"));
+        assert_eq!(fields["resource"], json!({ "host.name": "web-1" }));
+    }
+
+    #[test]
+    fn maps_json_records() {
+        let body = br#"{
+            "resourceLogs": [{
+                "resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "api"}}]},
+                "scopeLogs": [{
+                    "logRecords": [{
+                        "observedTimeUnixNano": "1700000000000000000",
+                        "severityText": "WARNING",
+                        "body": {"kvlistValue": {"values": [{"key": "n", "value": {"intValue": "7"}}]}},
+                        "attributes": [
+                            {"key": "ok", "value": {"boolValue": true}},
+                            {"key": "tags", "value": {"arrayValue": {"values": [{"doubleValue": 1.5}]}}}
+                        ],
+                        "traceId": "5b8efff798038103d269b633813fc60c"
+                    }]
+                }]
+            }]
+        }"#;
+
+        let log_messages = into_log_messages(decode_json(body).unwrap());
+        assert_eq!(log_messages.len(), 1);
+        let log = &log_messages[0];
+        assert_eq!(log.hash, "api");
+        // Without a severity number the text is kept for normalization at ingest
+        assert_eq!(log.level, "WARNING");
+        assert_eq!(log.message, r#"{"n":7}"#);
+        assert_eq!(log.target, "otel");
+        // Falls back to the observed time
+        assert_eq!(log.timestamp, "2023-11-14T22:13:20+00:00");
+        let fields = &log.fields.as_ref().unwrap().0;
+        assert_eq!(fields["ok"], json!(true));
+        assert_eq!(fields["tags"], json!([1.5]));
+        assert_eq!(fields["trace_id"], json!("5b8efff798038103d269b633813fc60c"));
+        assert!(!fields.contains_key("resource"));
+    }
+
+    #[test]
+    fn falls_back_to_unknown_service() {
+        let body = br#"{"resourceLogs": [
+            {"scopeLogs": [{"logRecords": [{"body": {"stringValue": "a"}}]}]},
+            {"resource": {"attributes": [{"key": "service.name", "value": {"stringValue": ""}}]},
+             "scopeLogs": [{"logRecords": [{}]}]}
+        ]}"#;
+
+        let log_messages = into_log_messages(decode_json(body).unwrap());
+        assert_eq!(log_messages.len(), 2);
+        assert!(log_messages.iter().all(|log| log.hash == "unknown_service"));
+        assert_eq!(log_messages[1].message, "");
+        assert!(log_messages[1].fields.is_none());
+    }
+
+    #[test]
+    fn rejects_undecodable_requests() {
+        assert!(decode_json(b"not json").is_none());
+        assert!(decode_protobuf(&[0xff, 0xff, 0xff]).is_none());
+    }
+}
@@ -0,0 +1,244 @@
+// protocol.rs
+
+//! Wire protocol shared by the TCP listener and the Rust client.
+//!
+//! A connection is a stream of newline-delimited JSON `LogMessage` records. A client may
+//! optionally open with a hello frame so both ends can agree on a protocol version and
+//! optional capabilities:
+//!
+//! ```text
+//! -> {"hello":{"protocol_version":1,"client":"ironlog-rust","client_version":"0.2.7","hash":"app","capabilities":["batch"]}}
+//! <- {"hello":{"protocol_version":1,"server":"ironlog","server_version":"0.2.7","capabilities":["batch"],"max_log_length":1000,"max_frame_length":1048576}}
+//! ```
+//!
+//! The server only answers when a hello is received, so clients that send raw JSON records
+//! (the ESP32 and Python clients) keep working unchanged.
+//!
+//! When the `msgpack` capability is granted, every frame after the server hello is a 4-byte
+//! big-endian length followed by that many bytes of a MessagePack-encoded `LogMessage` map.
+//! The same framing is spoken from the first byte on the optional binary listener port.
+//!
+//! Any frame may carry several records at once, either as an array of records or as a
+//! `{"batch":[...]}` envelope. Clients should only send batches once `batch` is granted,
+//! since older servers drop them.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::types::LogMessage;
+
+/// Highest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Client switches to length-prefixed MessagePack frames after the handshake.
+pub const CAP_MSGPACK: &str = "msgpack";
+/// Client coalesces several records into one frame.
+pub const CAP_BATCH: &str = "batch";
+
+/// Capabilities the server is able to grant.
+pub const SERVER_CAPABILITIES: &[&str] = &[CAP_MSGPACK, CAP_BATCH];
+
+/// Default for the largest line or binary frame the server reads (`--max-frame-length`).
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+/// How records are delimited on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON record per line.
+    JsonLines,
+    /// 4-byte big-endian length prefix followed by a MessagePack record.
+    MsgPack,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientHello {
+    pub protocol_version: u32,
+    /// Client library or language, e.g. `ironlog-rust`, `ironlog-esp32`, `python`.
+    pub client: String,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    #[serde(default)]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerHello {
+    pub protocol_version: u32,
+    pub server: String,
+    pub server_version: String,
+    /// Capabilities granted for this connection, a subset of the ones the client asked for.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    pub max_log_length: usize,
+    /// Longest frame the server reads, so batching clients can stay below it. Absent from
+    /// servers that predate it, which read up to `MAX_FRAME_LENGTH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_frame_length: Option<usize>,
+}
+
+impl ServerHello {
+    /// Answer `hello` with the highest common protocol version and the granted capabilities.
+    pub fn reply_to(hello: &ClientHello, max_log_length: usize, max_frame_length: usize) -> Self {
+        ServerHello {
+            protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+            server: "ironlog".to_string(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: hello
+                .capabilities
+                .iter()
+                .filter(|cap| SERVER_CAPABILITIES.contains(&cap.as_str()))
+                .cloned()
+                .collect(),
+            max_log_length,
+            max_frame_length: Some(max_frame_length),
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+}
+
+/// Envelope that tells a hello apart from a log record on the same stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloFrame<T> {
+    pub hello: T,
+}
+
+/// Returns the client hello if `line` is a hello frame.
+pub fn parse_client_hello(line: &str) -> Option<ClientHello> {
+    serde_json::from_str::<HelloFrame<ClientHello>>(line)
+        .ok()
+        .map(|frame| frame.hello)
+}
+
+#[derive(Deserialize)]
+struct BatchEnvelope {
+    batch: Vec<LogMessage>,
+}
+
+/// Decode the records carried by one JSON line: a record, an array of records or a batch envelope.
+pub fn decode_json_records(line: &str) -> Result<Vec<LogMessage>, serde_json::Error> {
+    match serde_json::from_str::<Value>(line)? {
+        Value::Array(records) => records.into_iter().map(serde_json::from_value).collect(),
+        Value::Object(mut envelope) if envelope.len() == 1 && envelope.contains_key("batch") => {
+            serde_json::from_value(envelope.remove("batch").unwrap_or_default())
+        }
+        record => Ok(vec![serde_json::from_value(record)?]),
+    }
+}
+
+/// Decode the records carried by one MessagePack frame body.
+pub fn decode_msgpack_records(frame: &[u8]) -> Result<Vec<LogMessage>, rmp_serde::decode::Error> {
+    match frame.first() {
+        // fixarray, array 16 and array 32 markers
+        Some(0x90..=0x9f | 0xdc | 0xdd) => rmp_serde::from_slice(frame),
+        _ => rmp_serde::from_slice::<LogMessage>(frame)
+            .map(|record| vec![record])
+            .or_else(|error| {
+                rmp_serde::from_slice::<BatchEnvelope>(frame)
+                    .map(|envelope| envelope.batch)
+                    .map_err(|_| error)
+            }),
+    }
+}
+
+/// Prefix an encoded MessagePack body with its length.
+pub fn frame_msgpack(body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Encode `record` as a length-prefixed MessagePack frame.
+pub fn encode_msgpack_frame<T: Serialize>(record: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    Ok(frame_msgpack(&rmp_serde::to_vec_named(record)?))
+}
+
+/// Join individually encoded JSON records into one newline-terminated array line.
+pub fn encode_json_batch(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut line = Vec::with_capacity(records.iter().map(|record| record.len() + 1).sum::<usize>() + 2);
+    line.push(b'[');
+    for (index, record) in records.iter().enumerate() {
+        if index > 0 {
+            line.push(b',');
+        }
+        line.extend_from_slice(record);
+    }
+    line.extend_from_slice(b"]\n");
+    line
+}
+
+/// Join individually encoded MessagePack records into one length-prefixed array frame.
+pub fn encode_msgpack_batch(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(records.iter().map(Vec::len).sum::<usize>() + 5);
+    rmp::encode::write_array_len(&mut body, records.len() as u32).expect("Writing to a Vec cannot fail");
+    for record in records {
+        body.extend_from_slice(record);
+    }
+    frame_msgpack(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record(message: &str) -> Value {
+        json!({"level": "INFO", "message": message, "target": "app", "module_path": null, "file": null, "line": null, "hash": "h"})
+    }
+
+    fn messages(records: &[LogMessage]) -> Vec<&str> {
+        records.iter().map(|record| record.message.as_str()).collect()
+    }
+
+    #[test]
+    fn decodes_json_records_in_every_shape() {
+        let single = record("one").to_string();
+        assert_eq!(messages(&decode_json_records(&single).unwrap()), ["one"]);
+
+        let array = json!([record("one"), record("two")]).to_string();
+        assert_eq!(messages(&decode_json_records(&array).unwrap()), ["one", "two"]);
+
+        let batch = json!({"batch": [record("one"), record("two")]}).to_string();
+        assert_eq!(messages(&decode_json_records(&batch).unwrap()), ["one", "two"]);
+
+        assert!(decode_json_records("{\"batch\":[],\"level\":\"INFO\"}").is_err());
+        assert!(decode_json_records(&json!([record("one"), {"level": "INFO"}]).to_string()).is_err());
+        assert!(decode_json_records("not json").is_err());
+    }
+
+    #[test]
+    fn decodes_msgpack_records_in_every_shape() {
+        let single = rmp_serde::to_vec_named(&record("one")).unwrap();
+        assert_eq!(messages(&decode_msgpack_records(&single).unwrap()), ["one"]);
+
+        let array = rmp_serde::to_vec_named(&json!([record("one"), record("two")])).unwrap();
+        assert_eq!(messages(&decode_msgpack_records(&array).unwrap()), ["one", "two"]);
+
+        let batch = rmp_serde::to_vec_named(&json!({"batch": [record("one"), record("two")]})).unwrap();
+        assert_eq!(messages(&decode_msgpack_records(&batch).unwrap()), ["one", "two"]);
+
+        assert!(decode_msgpack_records(&rmp_serde::to_vec_named(&json!({"level": "INFO"})).unwrap()).is_err());
+        assert!(decode_msgpack_records(&[0xc1]).is_err());
+        assert!(decode_msgpack_records(&[]).is_err());
+    }
+
+    #[test]
+    fn decodes_the_batches_it_encodes() {
+        let json: Vec<Vec<u8>> = ["one", "two", "three"].iter().map(|m| record(m).to_string().into_bytes()).collect();
+        let line = encode_json_batch(&json);
+        assert_eq!(line.last(), Some(&b'\n'));
+        let line = std::str::from_utf8(&line).unwrap().trim_end();
+        assert_eq!(messages(&decode_json_records(line).unwrap()), ["one", "two", "three"]);
+
+        let msgpack: Vec<Vec<u8>> = ["one", "two", "three"].iter().map(|m| rmp_serde::to_vec_named(&record(m)).unwrap()).collect();
+        let frame = encode_msgpack_batch(&msgpack);
+        let (length, body) = frame.split_at(4);
+        assert_eq!(u32::from_be_bytes(length.try_into().unwrap()) as usize, body.len());
+        assert_eq!(messages(&decode_msgpack_records(body).unwrap()), ["one", "two", "three"]);
+    }
+}
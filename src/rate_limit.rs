@@ -0,0 +1,173 @@
+// rate_limit.rs
+
+//! Record rate limits, so one node stuck in a loop can't crowd everyone else out.
+//!
+//! Every hash gets a token bucket refilled at `--max-hash-rate` records per second, and all
+//! records together share one refilled at `--max-total-rate`. Both allow a burst of one
+//! second's worth. Records arriving with an empty bucket are dropped and counted per hash, so
+//! the counts can be reported back into that hash's logs.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+}
+
+#[derive(Default)]
+struct HashState {
+    bucket: Option<TokenBucket>,
+    dropped: u64,
+}
+
+/// Per-hash and global record rate limits.
+pub struct RateLimiter {
+    max_hash_rate: Option<u64>,
+    total: Option<TokenBucket>,
+    hashes: HashMap<String, HashState>,
+}
+
+impl RateLimiter {
+    pub fn new(max_hash_rate: Option<u64>, max_total_rate: Option<u64>) -> Self {
+        RateLimiter {
+            max_hash_rate,
+            total: max_total_rate.map(|rate| TokenBucket::new(rate, Instant::now())),
+            hashes: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_hash_rate.is_some() || self.total.is_some()
+    }
+
+    /// Whether a record for `hash` may be stored now. Refused records are counted.
+    pub fn admit(&mut self, hash: &str, now: Instant) -> bool {
+        if !self.hashes.contains_key(hash) {
+            self.hashes.insert(hash.to_string(), HashState::default());
+        }
+        let state = self.hashes.get_mut(hash).unwrap();
+        let mut bucket = self
+            .max_hash_rate
+            .map(|rate| state.bucket.get_or_insert_with(|| TokenBucket::new(rate, now)));
+
+        if let Some(bucket) = bucket.as_mut() {
+            bucket.refill(now);
+        }
+        if let Some(total) = self.total.as_mut() {
+            total.refill(now);
+        }
+        // A record needs a token from both buckets, and only takes them if it gets both
+        let has_token = bucket.as_ref().is_none_or(|bucket| bucket.tokens >= 1.0)
+            && self.total.as_ref().is_none_or(|total| total.tokens >= 1.0);
+        if !has_token {
+            state.dropped += 1;
+            return false;
+        }
+
+        if let Some(bucket) = bucket {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(total) = self.total.as_mut() {
+            total.tokens -= 1.0;
+        }
+        true
+    }
+
+    /// Take the number of records dropped per hash since the last call, and forget hashes that
+    /// have gone quiet.
+    pub fn take_dropped(&mut self) -> Vec<(String, u64)> {
+        let now = Instant::now();
+        let mut dropped = Vec::new();
+        self.hashes.retain(|hash, state| {
+            if state.dropped > 0 {
+                dropped.push((hash.clone(), std::mem::take(&mut state.dropped)));
+            }
+            match state.bucket.as_mut() {
+                Some(bucket) => {
+                    bucket.refill(now);
+                    !bucket.is_full()
+                }
+                None => false,
+            }
+        });
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_a_burst_of_one_seconds_worth_per_hash() {
+        let mut limiter = RateLimiter::new(Some(3), None);
+        let now = Instant::now();
+        assert!((0..3).all(|_| limiter.admit("a", now)));
+        assert!(!limiter.admit("a", now));
+        // Other hashes have buckets of their own
+        assert!(limiter.admit("b", now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(Some(2), None);
+        let now = Instant::now();
+        assert!(limiter.admit("a", now) && limiter.admit("a", now));
+        assert!(!limiter.admit("a", now));
+        assert!(limiter.admit("a", now + Duration::from_millis(500)));
+        assert!(!limiter.admit("a", now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn total_rate_is_shared_by_all_hashes() {
+        let mut limiter = RateLimiter::new(None, Some(2));
+        let now = Instant::now();
+        assert!(limiter.admit("a", now));
+        assert!(limiter.admit("b", now));
+        assert!(!limiter.admit("c", now));
+    }
+
+    #[test]
+    fn refused_records_take_no_tokens() {
+        let mut limiter = RateLimiter::new(Some(1), Some(2));
+        let now = Instant::now();
+        assert!(limiter.admit("a", now));
+        // Refused by the hash bucket, so the total bucket keeps its token for `b`
+        assert!(!limiter.admit("a", now));
+        assert!(limiter.admit("b", now));
+    }
+
+    #[test]
+    fn reports_dropped_records_once() {
+        let mut limiter = RateLimiter::new(Some(1), None);
+        let now = Instant::now();
+        limiter.admit("a", now);
+        limiter.admit("a", now);
+        limiter.admit("a", now);
+        assert_eq!(limiter.take_dropped(), vec![("a".to_string(), 2)]);
+        assert!(limiter.take_dropped().is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+// retention.rs
+
+//! Retention limits enforced by the maintenance task.
+//!
+//! Every hash keeps at most `--max-log-count` logs, and with `--retention` logs older than the
+//! retention period are removed as well, so quiet hashes don't hold on to old logs forever.
+//! Retention rules (see `retention_rules`) override both for particular hashes and levels.
+//! With `--max-db-size` the oldest logs across all hashes are deleted until the database fits
+//! the budget, and the freed pages are handed back to the filesystem by incremental vacuum.
+//! The limits are applied by the sweep in `sweep`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::retention_rules::RetentionRule;
+
+/// How long logs are kept, written as a number and a unit: `90s`, `30m`, `12h`, `14d` or `2w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RetentionPeriod(Duration);
+
+const UNITS: [(char, u64); 5] = [('w', 7 * 86400), ('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+impl RetentionPeriod {
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for RetentionPeriod {
+    type Err = String;
+
+    fn from_str(period: &str) -> Result<Self, Self::Err> {
+        let period = period.trim();
+        let unit = period.chars().last().ok_or("empty retention period")?;
+        let (_, seconds) = UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .ok_or_else(|| format!("unknown unit in `{}`, expected one of s, m, h, d, w", period))?;
+        let count: u64 = period[..period.len() - 1]
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("invalid retention period `{}`", period))?;
+        Ok(RetentionPeriod(Duration::from_secs(count.saturating_mul(*seconds))))
+    }
+}
+
+impl TryFrom<String> for RetentionPeriod {
+    type Error = String;
+
+    fn try_from(period: String) -> Result<Self, Self::Error> {
+        period.parse()
+    }
+}
+
+impl From<RetentionPeriod> for String {
+    fn from(period: RetentionPeriod) -> Self {
+        period.to_string()
+    }
+}
+
+impl fmt::Display for RetentionPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let (unit, size) = UNITS
+            .iter()
+            .find(|(_, size)| seconds.is_multiple_of(*size))
+            .expect("every duration is a whole number of seconds");
+        write!(f, "{}{}", seconds / size, unit)
+    }
+}
+
+/// A size in bytes, written as a number with an optional unit: `500K`, `200MB`, `2G` or `1GiB`.
+/// Units are powers of 1024.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(u64);
+
+const BYTE_UNITS: [(&str, u64); 4] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10), ("", 1)];
+
+impl ByteSize {
+    pub fn as_bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        let size = size.trim();
+        let upper = size.to_ascii_uppercase();
+        let number = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let unit = match &upper[number.len()..] {
+            "" | "B" => "",
+            "K" | "KB" | "KIB" => "K",
+            "M" | "MB" | "MIB" => "M",
+            "G" | "GB" | "GIB" => "G",
+            _ => return Err(format!("unknown unit in `{}`, expected one of K, M, G", size)),
+        };
+        let (_, multiplier) = BYTE_UNITS.iter().find(|(name, _)| *name == unit).expect("listed above");
+        let count: u64 = number
+            .trim()
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("invalid size `{}`", size))?;
+        Ok(ByteSize(count.saturating_mul(*multiplier)))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (unit, size) = BYTE_UNITS
+            .iter()
+            .find(|(_, size)| self.0.is_multiple_of(*size))
+            .expect("every size is a whole number of bytes");
+        write!(f, "{}{}", self.0 / size, unit)
+    }
+}
+
+/// The limits in effect, as reported by `/api/log_info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionPolicy {
+    pub max_log_count: usize,
+    /// Retention period such as `14d`, if logs expire
+    pub retention: Option<String>,
+    pub retention_seconds: Option<u64>,
+    pub max_hashes: usize,
+    /// Database size budget such as `2G`, if there is one
+    pub max_db_size: Option<String>,
+    pub max_db_size_bytes: Option<u64>,
+    /// Rules for particular hashes and levels, in the order they are tried
+    pub rules: Vec<RetentionRule>,
+}
+
+impl RetentionPolicy {
+    pub fn new(config: &Config, rules: Vec<RetentionRule>) -> Self {
+        RetentionPolicy {
+            max_log_count: config.max_log_count,
+            retention: config.retention.map(|period| period.to_string()),
+            retention_seconds: config.retention.map(|period| period.as_duration().as_secs()),
+            max_hashes: config.max_hashes,
+            max_db_size: config.max_db_size.map(|size| size.to_string()),
+            max_db_size_bytes: config.max_db_size.map(|size| size.as_bytes()),
+            rules,
+        }
+    }
+}
+
+/// How the database file is used and how fast it fills, as reported by `/api/log_info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageInfo {
+    pub page_size: u64,
+    pub page_count: u64,
+    /// Pages left empty by deletes. They are reused before the file grows, and handed back to
+    /// the filesystem by incremental vacuum.
+    pub free_pages: u64,
+    /// Bytes in pages that hold data; this is what `--max-db-size` limits
+    pub used_bytes: u64,
+    /// Records stored per second over the last maintenance interval
+    pub ingest_rate: f64,
+    /// Seconds until `used_bytes` reaches `--max-db-size` at the current ingest rate, if the
+    /// database is growing towards a budget
+    pub seconds_until_full: Option<u64>,
+}
+
+impl StorageInfo {
+    pub async fn read(
+        db_pool: &SqlitePool,
+        max_db_size: Option<ByteSize>,
+        ingest_rate: f64,
+        log_count: i64,
+    ) -> Result<Self, sqlx::Error> {
+        let pages = PageUsage::read(db_pool).await?;
+        let used_bytes = pages.used_bytes();
+
+        // Assume new logs take as much room as the stored ones do on average
+        let seconds_until_full = max_db_size.and_then(|max| {
+            if log_count <= 0 || ingest_rate <= 0.0 {
+                return None;
+            }
+            let bytes_per_second = ingest_rate * used_bytes as f64 / log_count as f64;
+            Some((max.as_bytes().saturating_sub(used_bytes) as f64 / bytes_per_second) as u64)
+        });
+
+        Ok(StorageInfo {
+            page_size: pages.page_size,
+            page_count: pages.page_count,
+            free_pages: pages.free_pages,
+            used_bytes,
+            ingest_rate,
+            seconds_until_full,
+        })
+    }
+}
+
+pub(crate) struct PageUsage {
+    pub(crate) page_size: u64,
+    pub(crate) page_count: u64,
+    pub(crate) free_pages: u64,
+}
+
+impl PageUsage {
+    pub(crate) async fn read(db_pool: &SqlitePool) -> Result<Self, sqlx::Error> {
+        let pragma = |name: &'static str| async move {
+            sqlx::query_scalar::<_, i64>(name).fetch_one(db_pool).await.map(|value| value.max(0) as u64)
+        };
+        Ok(PageUsage {
+            page_size: pragma("PRAGMA page_size").await?,
+            page_count: pragma("PRAGMA page_count").await?,
+            free_pages: pragma("PRAGMA freelist_count").await?,
+        })
+    }
+
+    pub(crate) fn used_bytes(&self) -> u64 {
+        self.page_count.saturating_sub(self.free_pages) * self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retention_periods() {
+        let period = |text: &str| text.parse::<RetentionPeriod>().map(|period| period.as_duration().as_secs());
+        assert_eq!(period("90s"), Ok(90));
+        assert_eq!(period("30m"), Ok(1800));
+        assert_eq!(period("12h"), Ok(43200));
+        assert_eq!(period(" 14d "), Ok(14 * 86400));
+        assert_eq!(period("2w"), Ok(14 * 86400));
+        assert!(period("").is_err());
+        assert!(period("0d").is_err());
+        assert!(period("14").is_err());
+        assert!(period("1y").is_err());
+        assert!(period("-1d").is_err());
+    }
+
+    #[test]
+    fn displays_retention_periods_in_the_largest_unit() {
+        assert_eq!("14d".parse::<RetentionPeriod>().unwrap().to_string(), "2w");
+        assert_eq!("120m".parse::<RetentionPeriod>().unwrap().to_string(), "2h");
+        assert_eq!("90s".parse::<RetentionPeriod>().unwrap().to_string(), "90s");
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        let size = |text: &str| text.parse::<ByteSize>().map(|size| size.as_bytes());
+        assert_eq!(size("512"), Ok(512));
+        assert_eq!(size("512B"), Ok(512));
+        assert_eq!(size("500K"), Ok(500 * 1024));
+        assert_eq!(size("200mb"), Ok(200 << 20));
+        assert_eq!(size("1GiB"), Ok(1 << 30));
+        assert_eq!(size("2 G"), Ok(2 << 30));
+        assert!(size("0M").is_err());
+        assert!(size("2T").is_err());
+        assert!(size("M").is_err());
+        assert!(size("1.5G").is_err());
+    }
+
+    #[test]
+    fn displays_byte_sizes_in_the_largest_unit() {
+        assert_eq!("2048M".parse::<ByteSize>().unwrap().to_string(), "2G");
+        assert_eq!("1536K".parse::<ByteSize>().unwrap().to_string(), "1536K");
+        assert_eq!("1000".parse::<ByteSize>().unwrap().to_string(), "1000");
+    }
+}
@@ -0,0 +1,223 @@
+// retention_rules.rs
+
+//! Retention rules for particular hashes and levels, on top of the global limits.
+//!
+//! Rules are kept in the JSON file given with `--retention-rules` and can be replaced through
+//! `/api/retention_rules`, which writes the file back. A rule matches hashes by name or by a
+//! pattern where `*` stands for any run of characters, and optionally a single level. For each
+//! hash and level the first matching rule that sets a limit wins, so specific rules go first:
+//!
+//! ```json
+//! [
+//!     { "hash": "edge-*", "level": "DEBUG", "max_age": "1h" },
+//!     { "level": "ERROR", "max_age": "90d" },
+//!     { "hash": "gateway", "max_log_count": 5000 }
+//! ]
+//! ```
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::levels;
+use crate::retention::RetentionPeriod;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRule {
+    /// Hash name or pattern; the rule applies to every hash if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Level the rule is limited to, any spelling `levels::normalize` understands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    /// Age after which matching logs are deleted, overriding `--retention`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<RetentionPeriod>,
+    /// Logs kept per matching hash (or per level within it when `level` is set), overriding
+    /// `--max-log-count`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_log_count: Option<usize>,
+}
+
+impl RetentionRule {
+    fn matches(&self, hash: &str, level: Option<&str>) -> bool {
+        self.hash.as_deref().is_none_or(|pattern| matches_pattern(pattern, hash))
+            && self.level.as_deref().is_none_or(|rule_level| Some(rule_level) == level)
+    }
+}
+
+/// Whether `hash` matches `pattern`, where each `*` matches any run of characters.
+fn matches_pattern(pattern: &str, hash: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = hash.strip_prefix(first) else { return false };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else { return rest.is_empty() };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The limits that apply to one hash, resolved from the rules and the global options.
+pub(crate) struct HashLimits<'a> {
+    rules: &'a [RetentionRule],
+    hash: &'a str,
+    config: &'a Config,
+}
+
+impl<'a> HashLimits<'a> {
+    pub(crate) fn new(rules: &'a [RetentionRule], hash: &'a str, config: &'a Config) -> Self {
+        HashLimits { rules, hash, config }
+    }
+
+    /// Age after which logs of `level` are deleted, if they expire.
+    pub(crate) fn max_age(&self, level: Option<&str>) -> Option<RetentionPeriod> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(self.hash, level))
+            .find_map(|rule| rule.max_age)
+            .or(self.config.retention)
+    }
+
+    /// Logs of `level` kept, if a rule limits that level on its own.
+    pub(crate) fn max_level_count(&self, level: Option<&str>) -> Option<usize> {
+        level?;
+        self.rules
+            .iter()
+            .filter(|rule| rule.level.is_some() && rule.matches(self.hash, level))
+            .find_map(|rule| rule.max_log_count)
+    }
+
+    /// Logs kept in total.
+    pub(crate) fn max_log_count(&self) -> usize {
+        self.rules
+            .iter()
+            .filter(|rule| rule.level.is_none() && rule.matches(self.hash, None))
+            .find_map(|rule| rule.max_log_count)
+            .unwrap_or(self.config.max_log_count)
+    }
+}
+
+/// The rules in effect, shared between the maintenance task and the API.
+#[derive(Clone)]
+pub struct RetentionRules {
+    path: Option<PathBuf>,
+    rules: Arc<RwLock<Vec<RetentionRule>>>,
+    aliases: Vec<(String, String)>,
+}
+
+impl RetentionRules {
+    /// Load the rules from `--retention-rules`. A missing file means no rules yet.
+    pub fn load(config: &Config) -> Result<Self, String> {
+        let mut store = RetentionRules {
+            path: config.retention_rules.clone(),
+            rules: Arc::new(RwLock::new(Vec::new())),
+            aliases: config.level_aliases.clone(),
+        };
+        let Some(path) = &store.path else { return Ok(store) };
+        if !path.exists() {
+            return Ok(store);
+        }
+
+        let contents = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let rules: Vec<RetentionRule> =
+            serde_json::from_str(&contents).map_err(|e| format!("invalid rules in {}: {}", path.display(), e))?;
+        let rules = store.validate(rules).map_err(|e| format!("invalid rules in {}: {}", path.display(), e))?;
+        store.rules = Arc::new(RwLock::new(rules));
+        Ok(store)
+    }
+
+    pub fn rules(&self) -> Vec<RetentionRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Replace the rules, saving them to the rules file if there is one.
+    pub fn replace(&self, rules: Vec<RetentionRule>) -> Result<Vec<RetentionRule>, ReplaceError> {
+        let rules = self.validate(rules).map_err(ReplaceError::Invalid)?;
+
+        // Hold the lock while saving so concurrent updates can't leave the file and memory apart
+        let mut current = self.rules.write().unwrap();
+        if let Some(path) = &self.path {
+            let contents = serde_json::to_string_pretty(&rules).expect("rules always serialize");
+            let temp_path = path.with_extension("tmp");
+            fs::write(&temp_path, contents + "\n")
+                .and_then(|_| fs::rename(&temp_path, path))
+                .map_err(|e| ReplaceError::Save(format!("failed to write {}: {}", path.display(), e)))?;
+        }
+        *current = rules.clone();
+        Ok(rules)
+    }
+
+    /// Check that every rule limits something, and store levels by their canonical name.
+    fn validate(&self, mut rules: Vec<RetentionRule>) -> Result<Vec<RetentionRule>, String> {
+        for (index, rule) in rules.iter_mut().enumerate() {
+            if rule.max_age.is_none() && rule.max_log_count.is_none() {
+                return Err(format!("rule {} sets neither `max_age` nor `max_log_count`", index + 1));
+            }
+            if rule.hash.as_deref().is_some_and(str::is_empty) {
+                return Err(format!("rule {} has an empty `hash`", index + 1));
+            }
+            if let Some(level) = &rule.level {
+                rule.level = Some(levels::normalize(level, &self.aliases).0);
+            }
+        }
+        Ok(rules)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplaceError {
+    /// The rules were refused and nothing changed
+    Invalid(String),
+    /// The rules could not be written to the rules file and nothing changed
+    Save(String),
+}
+
+impl std::fmt::Display for ReplaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplaceError::Invalid(message) | ReplaceError::Save(message) => f.write_str(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_names_exactly_without_wildcards() {
+        assert!(matches_pattern("gateway", "gateway"));
+        assert!(!matches_pattern("gateway", "gateway-2"));
+        assert!(!matches_pattern("gateway", "my-gateway"));
+    }
+
+    #[test]
+    fn wildcards_match_any_run_of_characters() {
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("edge-*", "edge-"));
+        assert!(matches_pattern("edge-*", "edge-eu-1"));
+        assert!(!matches_pattern("edge-*", "core-edge-1"));
+        assert!(matches_pattern("*-prod", "api-prod"));
+        assert!(!matches_pattern("*-prod", "api-prod-2"));
+        assert!(matches_pattern("edge-*-prod", "edge-eu-prod"));
+        assert!(matches_pattern("a*b*c", "a-b-b-c"));
+        assert!(!matches_pattern("a*b*c", "a-c-b"));
+    }
+
+    #[test]
+    fn wildcards_do_not_reuse_characters() {
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("ab*ba", "abba"));
+    }
+}
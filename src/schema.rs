@@ -0,0 +1,228 @@
+// schema.rs
+
+//! Database schema, kept up to date by numbered migrations.
+//!
+//! The migrations are the SQL files in `migrations/`, built into the binary, plus data fixes
+//! SQL can't express. Each runs once, in its own transaction, and is recorded in the
+//! `schema_version` table, so starting a newer version upgrades an existing database in place.
+//! To change the schema, add a file and an entry to `MIGRATIONS`; never edit a migration that
+//! has been released.
+
+use sqlx::{SqliteConnection, SqlitePool};
+use crate::levels;
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql(&'static str),
+    /// Give rows written before levels were normalized a canonical level and severity
+    NormalizeLevels,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "baseline tables",
+        step: Step::Sql(include_str!("../migrations/0001_baseline.sql")),
+    },
+    Migration {
+        version: 2,
+        description: "log indexes",
+        step: Step::Sql(include_str!("../migrations/0002_log_indexes.sql")),
+    },
+    Migration {
+        version: 3,
+        description: "normalize stored levels",
+        step: Step::NormalizeLevels,
+    },
+];
+
+/// Columns added to `logs` before there were migrations, which a database from then may lack.
+const UNVERSIONED_COLUMNS: &[(&str, &str)] = &[
+    ("fields", "TEXT"),
+    ("severity", "INTEGER"),
+    ("peer", "TEXT"),
+    ("session_id", "TEXT"),
+];
+
+/// Bring the database up to the latest schema, resolving stored levels with `level_aliases`.
+/// Panics if that fails, or if the database was written by a newer version.
+pub async fn migrate(pool: &SqlitePool, level_aliases: &[(String, String)]) {
+    sqlx::query("
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT,
+            applied_at TEXT
+        )
+    ")
+    .execute(pool)
+    .await
+    .expect("Failed to create schema_version table.");
+
+    let current: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to read the schema version");
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        panic!(
+            "The database has schema version {}, but this version of ironlog only knows up to {}",
+            current, latest
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        println!("Migrating the database to schema version {}: {}", migration.version, migration.description);
+        let mut transaction = pool.begin().await.expect("Failed to start a migration");
+        // Completed in the transaction that records version 1, so a crash can't leave a
+        // database from before migrations half adopted
+        if migration.version == 1 {
+            adopt_unversioned(&mut transaction).await;
+        }
+        let applied = match migration.step {
+            Step::Sql(sql) => sqlx::query(sql).execute(&mut *transaction).await.map(|_| ()),
+            Step::NormalizeLevels => normalize_levels(&mut transaction, level_aliases).await,
+        };
+        applied.unwrap_or_else(|e| panic!("Failed to apply schema migration {}: {}", migration.version, e));
+        sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(&mut *transaction)
+            .await
+            .expect("Failed to record the schema version");
+        transaction.commit().await.expect("Failed to commit a migration");
+    }
+}
+
+/// Give a `logs` table from before migrations the columns the baseline expects, so the
+/// baseline migration finds it complete.
+async fn adopt_unversioned(connection: &mut SqliteConnection) {
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info('logs')")
+        .fetch_all(&mut *connection)
+        .await
+        .expect("Failed to inspect table columns");
+    if columns.is_empty() {
+        return;
+    }
+
+    for (column, definition) in UNVERSIONED_COLUMNS {
+        if !columns.iter().any(|name| name == column) {
+            sqlx::query(&format!("ALTER TABLE logs ADD COLUMN {} {}", column, definition))
+                .execute(&mut *connection)
+                .await
+                .expect("Failed to add column");
+        }
+    }
+}
+
+async fn normalize_levels(connection: &mut SqliteConnection, aliases: &[(String, String)]) -> sqlx::Result<()> {
+    let stored: Vec<Option<String>> = sqlx::query_scalar("SELECT DISTINCT level FROM logs WHERE severity IS NULL")
+        .fetch_all(&mut *connection)
+        .await?;
+
+    for stored_level in stored {
+        let (level, severity) = levels::normalize(stored_level.as_deref().unwrap_or(""), aliases);
+        sqlx::query("UPDATE logs SET level = ?, severity = ? WHERE level IS ? AND severity IS NULL")
+            .bind(level)
+            .bind(severity)
+            .bind(stored_level)
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap()
+    }
+
+    async fn names(pool: &SqlitePool, query: &str) -> Vec<String> {
+        sqlx::query_scalar(query).fetch_all(pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn creates_a_new_database() {
+        let pool = memory_pool().await;
+        migrate(&pool, &[]).await;
+        migrate(&pool, &[]).await;
+
+        let versions: Vec<i64> = sqlx::query_scalar("SELECT version FROM schema_version ORDER BY version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(versions, MIGRATIONS.iter().map(|migration| migration.version).collect::<Vec<_>>());
+        let tables = names(&pool, "SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name").await;
+        for table in ["archived_logs", "logs", "rejected_lines", "schema_version"] {
+            assert!(tables.iter().any(|name| name == table), "missing table {}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_database_from_before_migrations() {
+        let pool = memory_pool().await;
+        // The table as the first releases created it
+        sqlx::query("
+            CREATE TABLE logs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                level TEXT,
+                message TEXT,
+                target TEXT,
+                module_path TEXT,
+                file TEXT,
+                line INTEGER,
+                hash TEXT,
+                timestamp TEXT
+            )
+        ")
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("
+            INSERT INTO logs (level, message, target, hash, timestamp) VALUES
+                ('warning', 'kept', 't', 'node', '2024-01-01T00:00:00+00:00'),
+                ('oops', 'aliased', 't', 'node', '2024-01-01T00:00:01+00:00')
+        ")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        migrate(&pool, &[("OOPS".to_string(), "ERROR".to_string())]).await;
+
+        let columns = names(&pool, "SELECT name FROM pragma_table_info('logs')").await;
+        for (column, _) in UNVERSIONED_COLUMNS {
+            assert!(columns.iter().any(|name| name == column), "missing column {}", column);
+        }
+        let indexes = names(&pool, "SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'logs'").await;
+        assert!(indexes.iter().any(|name| name == "idx_logs_hash_timestamp"));
+        let rows: Vec<(String, String, i64)> = sqlx::query_as("SELECT message, level, severity FROM logs ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            [("kept".to_string(), "WARN".to_string(), 4), ("aliased".to_string(), "ERROR".to_string(), 5)]
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "only knows up to")]
+    async fn refuses_a_newer_schema() {
+        let pool = memory_pool().await;
+        migrate(&pool, &[]).await;
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES (1000, 'from the future')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        migrate(&pool, &[]).await;
+    }
+}
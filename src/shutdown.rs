@@ -0,0 +1,115 @@
+// shutdown.rs
+
+//! Coordinated shutdown of the listeners and the database pipeline.
+//!
+//! Shutdown moves through two phases. While `Draining`, listeners stop accepting and open
+//! connections get a grace period to finish sending. Once `Closing`, the remaining connections
+//! are dropped and the writers close their channels, store everything still queued and exit.
+
+use std::future::Future;
+
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::access::ConnectionLimiter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    Draining,
+    Closing,
+}
+
+/// Lets listeners, connections and writers follow the shutdown.
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<Phase>);
+
+impl ShutdownSignal {
+    /// Resolves once shutdown has reached `phase`.
+    pub async fn reached(&self, phase: Phase) {
+        let mut receiver = self.0.clone();
+        // An error means the sender is gone, which only happens when the server exits
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Whether shutdown has already reached `phase`.
+    pub fn has_reached(&self, phase: Phase) -> bool {
+        *self.0.borrow() >= phase
+    }
+
+    /// Run `task` until it finishes or shutdown reaches `phase`, whichever comes first.
+    pub async fn run_until<T>(&self, phase: Phase, task: impl Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            output = task => Some(output),
+            _ = self.reached(phase) => None,
+        }
+    }
+}
+
+/// Receive the next message, closing the channel once shutdown reaches `Closing` so the
+/// messages still queued are handed out before `None`.
+pub(crate) async fn recv_until_closed<T>(
+    receiver: &mut mpsc::Receiver<T>,
+    shutdown: &ShutdownSignal,
+    closed: &mut bool,
+) -> Option<T> {
+    loop {
+        if *closed {
+            return receiver.recv().await;
+        }
+        tokio::select! {
+            message = receiver.recv() => return message,
+            _ = shutdown.reached(Phase::Closing) => {
+                receiver.close();
+                *closed = true;
+            }
+        }
+    }
+}
+
+/// Handle that stops the listeners and flushes the pipeline.
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+    connections: ConnectionLimiter,
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(connections: ConnectionLimiter) -> (Self, ShutdownSignal) {
+        let (phase, receiver) = watch::channel(Phase::Running);
+        let shutdown = Shutdown {
+            phase,
+            connections,
+            writers: Vec::new(),
+        };
+        (shutdown, ShutdownSignal(receiver))
+    }
+
+    /// Wait for a writer task to finish before shutdown completes.
+    pub(crate) fn add_writer(&mut self, writer: JoinHandle<()>) {
+        self.writers.push(writer);
+    }
+
+    /// Stop accepting, give open connections up to `grace` to finish, then drain and
+    /// commit everything queued for the database.
+    pub async fn run(self, grace: Duration) {
+        self.phase.send_replace(Phase::Draining);
+
+        let deadline = Instant::now() + grace;
+        while self.connections.open_connections() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(100)).await;
+        }
+        let open = self.connections.open_connections();
+        if open > 0 {
+            println!("Closing {} connection(s) still open after the grace period", open);
+        }
+
+        self.phase.send_replace(Phase::Closing);
+        for writer in self.writers {
+            if let Err(e) = writer.await {
+                eprintln!("Database writer failed during shutdown: {}", e);
+            }
+        }
+    }
+}
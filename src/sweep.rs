@@ -0,0 +1,685 @@
+// sweep.rs
+
+//! The retention sweep run by the maintenance task.
+//!
+//! Rather than counting every hash each time, the sweep works from per-hash and per-level row
+//! counts kept in memory: the writer adds what it stores and the sweep subtracts what it
+//! deletes. The counts are read from the table again now and then, and whenever something
+//! else changed the table behind their back. Expired logs of every hash and level go in one
+//! set-based statement, and only the hashes the counts show over a limit are trimmed. All
+//! deletes go through the `(hash, timestamp)` or `(hash, level, timestamp)` index and are done
+//! in chunks of `SWEEP_CHUNK` rows with a pause in between, so the writer never waits long.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::query::QueryAs;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Sqlite, SqlitePool};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::config::Config;
+use crate::retention::{ByteSize, PageUsage, RetentionPeriod};
+use crate::retention_rules::{HashLimits, RetentionRule};
+use crate::types::LogMessage;
+
+/// Rows deleted per statement.
+const SWEEP_CHUNK: u64 = 5000;
+
+/// Pause between chunks, letting the writer in.
+const CHUNK_PAUSE: Duration = Duration::from_millis(10);
+
+/// Sweeps after which the counts are read from the table again, correcting any drift.
+const RELOAD_COUNTS_EVERY: u32 = 60;
+
+/// Hash and level of a deleted row, as returned by `DELETE ... RETURNING`.
+type DeletedRow = (String, Option<String>);
+
+/// Stored rows per hash and level.
+pub struct LogCounts {
+    by_hash: HashMap<String, HashMap<Option<String>, i64>>,
+    /// Set when the table was changed without updating the counts
+    stale: bool,
+    sweeps_since_reload: u32,
+}
+
+impl LogCounts {
+    pub(crate) fn new() -> Self {
+        LogCounts {
+            by_hash: HashMap::new(),
+            stale: true,
+            sweeps_since_reload: 0,
+        }
+    }
+
+    /// Count rows just stored.
+    pub(crate) fn add<'a>(&mut self, logs: impl IntoIterator<Item = &'a LogMessage>) {
+        for log in logs {
+            *self
+                .by_hash
+                .entry(log.hash.clone())
+                .or_default()
+                .entry(Some(log.level.clone()))
+                .or_default() += 1;
+        }
+    }
+
+    pub(crate) fn subtract(&mut self, hash: &str, level: &Option<String>, count: i64) {
+        if let Some(count_of) = self.by_hash.get_mut(hash).and_then(|levels| levels.get_mut(level)) {
+            *count_of -= count;
+        }
+    }
+
+    pub(crate) fn remove_hash(&mut self, hash: &str) {
+        self.by_hash.remove(hash);
+    }
+
+    /// Have the next sweep read the counts from the table again.
+    pub(crate) fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Hashes with rows stored, or `None` while the counts are stale.
+    pub(crate) fn stored_hashes(&self) -> Option<HashSet<String>> {
+        if self.stale {
+            return None;
+        }
+        let stored = self.by_hash.iter().filter(|(_, levels)| levels.values().any(|count| *count > 0));
+        Some(stored.map(|(hash, _)| hash.clone()).collect())
+    }
+
+    fn total(&self) -> i64 {
+        self.by_hash.values().flat_map(|levels| levels.values()).sum()
+    }
+
+    fn hash_levels(&self, hash: &str) -> Vec<(Option<String>, i64)> {
+        self.by_hash
+            .get(hash)
+            .map(|levels| levels.iter().map(|(level, count)| (level.clone(), *count)).collect())
+            .unwrap_or_default()
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, Vec<(Option<String>, i64)>> {
+        self.by_hash.keys().map(|hash| (hash.clone(), self.hash_levels(hash))).collect()
+    }
+}
+
+/// Timing and results of the retention sweeps, as reported by `/api/metrics`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepMetrics {
+    pub sweeps: u64,
+    pub max_duration_ms: f64,
+    pub total_deleted: u64,
+    pub last: Option<SweepReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepReport {
+    pub started_at: String,
+    pub duration_ms: f64,
+    /// Delete statements run
+    pub chunks: u64,
+    /// The slowest delete statement, about the longest the writer had to wait
+    pub longest_chunk_ms: f64,
+    /// Whether the counts were read from the table first
+    pub counts_reloaded: bool,
+    /// Logs older than their retention period
+    pub expired: u64,
+    /// Logs over a count limit
+    pub trimmed: u64,
+    /// Oldest logs deleted to fit `--max-db-size`
+    pub over_size: u64,
+    /// Archived logs deleted to fit `--max-db-size`, which go before any live log
+    pub archived_over_size: u64,
+}
+
+/// Apply every retention limit once.
+pub(crate) async fn sweep(
+    db_pool: &SqlitePool,
+    rules: &[RetentionRule],
+    config: &Config,
+    counts: &Mutex<LogCounts>,
+    metrics: &Mutex<SweepMetrics>,
+) {
+    let started = Instant::now();
+    let started_at = Utc::now();
+    let mut sweep = Sweep {
+        db_pool,
+        counts,
+        chunks: 0,
+        longest_chunk: Duration::ZERO,
+        archived_over_size: 0,
+    };
+
+    let counts_reloaded = {
+        let mut counts = counts.lock().unwrap();
+        counts.sweeps_since_reload += 1;
+        counts.stale || counts.sweeps_since_reload >= RELOAD_COUNTS_EVERY
+    };
+    if counts_reloaded {
+        if let Err(e) = reload_counts(db_pool, counts).await {
+            eprintln!("Failed to count logs per hash and level: {}", e);
+            return;
+        }
+    }
+
+    let expired = sweep.delete_expired(rules, config, started_at).await;
+    let trimmed = sweep.trim_to_counts(rules, config).await;
+    let over_size = match config.max_db_size {
+        Some(max_size) => sweep.fit_max_db_size(max_size).await,
+        None => 0,
+    };
+    print_deleted(expired, trimmed, over_size, sweep.archived_over_size, config);
+
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let mut metrics = metrics.lock().unwrap();
+    metrics.sweeps += 1;
+    metrics.max_duration_ms = metrics.max_duration_ms.max(duration_ms);
+    metrics.total_deleted += expired + trimmed + over_size + sweep.archived_over_size;
+    metrics.last = Some(SweepReport {
+        started_at: started_at.to_rfc3339(),
+        duration_ms,
+        chunks: sweep.chunks,
+        longest_chunk_ms: sweep.longest_chunk.as_secs_f64() * 1000.0,
+        counts_reloaded,
+        expired,
+        trimmed,
+        over_size,
+        archived_over_size: sweep.archived_over_size,
+    });
+}
+
+fn print_deleted(expired: u64, trimmed: u64, over_size: u64, archived_over_size: u64, config: &Config) {
+    if expired > 0 {
+        println!("Deleted {} log(s) older than their retention period", expired);
+    }
+    if trimmed > 0 {
+        println!("Deleted {} log(s) over their hash's log count limit", trimmed);
+    }
+    if let Some(max_size) = config.max_db_size.filter(|_| archived_over_size > 0) {
+        println!("Deleted {} of the oldest archived log(s) to stay under --max-db-size {}", archived_over_size, max_size);
+    }
+    if let Some(max_size) = config.max_db_size.filter(|_| over_size > 0) {
+        println!("Deleted {} of the oldest log(s) to stay under --max-db-size {}", over_size, max_size);
+    }
+}
+
+async fn reload_counts(db_pool: &SqlitePool, counts: &Mutex<LogCounts>) -> Result<(), sqlx::Error> {
+    let groups: Vec<(String, Option<String>, i64)> =
+        sqlx::query_as("SELECT hash, level, COUNT(*) FROM logs GROUP BY hash, level")
+            .fetch_all(db_pool)
+            .await?;
+
+    let mut by_hash: HashMap<String, HashMap<Option<String>, i64>> = HashMap::new();
+    for (hash, level, count) in groups {
+        by_hash.entry(hash).or_default().insert(level, count);
+    }
+    let mut counts = counts.lock().unwrap();
+    counts.by_hash = by_hash;
+    counts.stale = false;
+    counts.sweeps_since_reload = 0;
+    Ok(())
+}
+
+struct Sweep<'a> {
+    db_pool: &'a SqlitePool,
+    counts: &'a Mutex<LogCounts>,
+    chunks: u64,
+    longest_chunk: Duration,
+    archived_over_size: u64,
+}
+
+impl<'a> Sweep<'a> {
+    /// Delete the logs of every hash and level older than their retention period.
+    async fn delete_expired(&mut self, rules: &[RetentionRule], config: &Config, now: DateTime<Utc>) -> u64 {
+        let groups = self.counts.lock().unwrap().snapshot();
+        let mut cutoffs = Vec::new();
+        for (hash, levels) in &groups {
+            let limits = HashLimits::new(rules, hash, config);
+            for (level, _) in levels {
+                if let Some(cutoff) = limits.max_age(level.as_deref()).and_then(|period| cutoff(period, now)) {
+                    cutoffs.push(serde_json::json!([hash, level, cutoff]));
+                }
+            }
+        }
+        if cutoffs.is_empty() {
+            return 0;
+        }
+        let cutoffs = serde_json::Value::Array(cutoffs).to_string();
+
+        // Timestamps are RFC 3339 text, which sorts like the time it stands for. The cross
+        // join keeps SQLite looking up each group in the index instead of scanning the logs.
+        self.delete_in_chunks(u64::MAX, true, |limit| {
+            sqlx::query_as(
+                "DELETE FROM logs WHERE id IN (
+                    SELECT logs.id
+                    FROM json_each(?) AS expiry
+                    CROSS JOIN logs
+                    WHERE logs.hash = expiry.value ->> 0
+                      AND logs.level IS expiry.value ->> 1
+                      AND logs.timestamp < expiry.value ->> 2
+                    LIMIT ?
+                )
+                RETURNING hash, level",
+            )
+            .bind(&cutoffs)
+            .bind(limit)
+        })
+        .await
+    }
+
+    /// Trim the hashes and levels the counts show over their limit to their newest logs.
+    async fn trim_to_counts(&mut self, rules: &[RetentionRule], config: &Config) -> u64 {
+        let groups = self.counts.lock().unwrap().snapshot();
+        let mut trimmed = 0;
+
+        for (hash, levels) in &groups {
+            let limits = HashLimits::new(rules, hash, config);
+            for (level, count) in levels {
+                let Some(keep) = limits.max_level_count(level.as_deref()) else { continue };
+                if *count <= keep as i64 {
+                    continue;
+                }
+                trimmed += self
+                    .delete_in_chunks(u64::MAX, true, |limit| {
+                        sqlx::query_as(
+                            "DELETE FROM logs WHERE id IN (
+                                SELECT id FROM logs WHERE hash = ? AND level IS ?
+                                ORDER BY timestamp DESC LIMIT ? OFFSET ?
+                            )
+                            RETURNING hash, level",
+                        )
+                        .bind(hash)
+                        .bind(level)
+                        .bind(limit)
+                        .bind(keep as i64)
+                    })
+                    .await;
+            }
+
+            let keep = limits.max_log_count();
+            let total: i64 = self.counts.lock().unwrap().hash_levels(hash).iter().map(|(_, count)| count).sum();
+            if total <= keep as i64 {
+                continue;
+            }
+            trimmed += self
+                .delete_in_chunks(u64::MAX, true, |limit| {
+                    sqlx::query_as(
+                        "DELETE FROM logs WHERE id IN (
+                            SELECT id FROM logs WHERE hash = ?
+                            ORDER BY timestamp DESC LIMIT ? OFFSET ?
+                        )
+                        RETURNING hash, level",
+                    )
+                    .bind(hash)
+                    .bind(limit)
+                    .bind(keep as i64)
+                })
+                .await;
+        }
+        trimmed
+    }
+
+    /// Delete the oldest logs across all hashes until the database fits in `max_size`, then
+    /// hand the freed pages back to the filesystem. Archived logs go first, as nothing else
+    /// trims them and they would otherwise push every live log out.
+    async fn fit_max_db_size(&mut self, max_size: ByteSize) -> u64 {
+        let max_bytes = max_size.as_bytes();
+        let mut deleted = 0;
+        let mut free_pages;
+
+        loop {
+            let pages = match PageUsage::read(self.db_pool).await {
+                Ok(pages) => pages,
+                Err(e) => {
+                    eprintln!("Failed to read the database size: {}", e);
+                    return deleted;
+                }
+            };
+            let used_bytes = pages.used_bytes();
+            free_pages = pages.free_pages;
+            if used_bytes <= max_bytes {
+                break;
+            }
+
+            let log_count = self.counts.lock().unwrap().total();
+            let archived_count: i64 = match sqlx::query_scalar("SELECT COUNT(*) FROM archived_logs")
+                .fetch_one(self.db_pool)
+                .await
+            {
+                Ok(count) => count,
+                Err(e) => {
+                    eprintln!("Failed to count archived logs: {}", e);
+                    return deleted;
+                }
+            };
+            let row_count = log_count + archived_count;
+            if row_count <= 0 {
+                eprintln!(
+                    "Database uses {} bytes with no logs left to delete, over the --max-db-size of {}",
+                    used_bytes, max_size
+                );
+                break;
+            }
+
+            // Estimate how many logs hold the excess, plus 1% more so one or two rounds do
+            let bytes_per_log = (used_bytes / row_count as u64).max(1);
+            let excess = ((used_bytes - max_bytes) / bytes_per_log + row_count as u64 / 100).max(100);
+
+            // Ids grow with every insert, so the lowest ids are the oldest logs
+            let removed = if archived_count > 0 {
+                let removed = self
+                    .delete_in_chunks(excess.min(archived_count as u64), false, |limit| {
+                        sqlx::query_as(
+                            "DELETE FROM archived_logs WHERE id IN (SELECT id FROM archived_logs ORDER BY id LIMIT ?)
+                            RETURNING hash, level",
+                        )
+                        .bind(limit)
+                    })
+                    .await;
+                self.archived_over_size += removed;
+                removed
+            } else {
+                let removed = self
+                    .delete_in_chunks(excess, true, |limit| {
+                        sqlx::query_as("DELETE FROM logs WHERE id IN (SELECT id FROM logs ORDER BY id LIMIT ?) RETURNING hash, level")
+                            .bind(limit)
+                    })
+                    .await;
+                deleted += removed;
+                removed
+            };
+            if removed == 0 {
+                break;
+            }
+        }
+
+        // Shrink the file by the free pages, and the WAL the deletes went through
+        if deleted > 0 || free_pages > 0 {
+            if let Err(e) = sqlx::query("PRAGMA incremental_vacuum;").execute(self.db_pool).await {
+                eprintln!("Failed to reclaim free pages: {}", e);
+            }
+            if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE);").execute(self.db_pool).await {
+                eprintln!("Failed to checkpoint the WAL: {}", e);
+            }
+        }
+        deleted
+    }
+
+    /// Run a delete built by `chunk` for at most `SWEEP_CHUNK` rows at a time, until it deletes
+    /// fewer or `max_rows` are gone. Returns the rows deleted, which are taken off the counts
+    /// when `counted` (rows of `logs`).
+    async fn delete_in_chunks<'q, F>(&mut self, max_rows: u64, counted: bool, chunk: F) -> u64
+    where
+        F: Fn(i64) -> QueryAs<'q, Sqlite, DeletedRow, SqliteArguments<'q>>,
+    {
+        let mut deleted = 0;
+        while deleted < max_rows {
+            let limit = SWEEP_CHUNK.min(max_rows - deleted);
+            let started = Instant::now();
+            let rows = match chunk(limit as i64).fetch_all(self.db_pool).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!("Failed to delete logs during the retention sweep: {}", e);
+                    break;
+                }
+            };
+            self.chunks += 1;
+            self.longest_chunk = self.longest_chunk.max(started.elapsed());
+            let chunk_rows = rows.len() as u64;
+            deleted += chunk_rows;
+            if counted {
+                self.subtract_deleted(rows);
+            }
+
+            if chunk_rows < limit {
+                break;
+            }
+            sleep(CHUNK_PAUSE).await;
+        }
+        deleted
+    }
+
+    fn subtract_deleted(&self, rows: Vec<DeletedRow>) {
+        let mut deleted: HashMap<DeletedRow, i64> = HashMap::new();
+        for row in rows {
+            *deleted.entry(row).or_default() += 1;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        for ((hash, level), count) in deleted {
+            counts.subtract(&hash, &level, count);
+        }
+    }
+}
+
+/// Oldest timestamp kept by `period`, as of `now`.
+fn cutoff(period: RetentionPeriod, now: DateTime<Utc>) -> Option<String> {
+    let period = chrono::Duration::from_std(period.as_duration()).ok()?;
+    let cutoff = now.checked_sub_signed(period)?;
+    // Same form as the stored timestamps, see `normalize_timestamp`
+    Some(cutoff.to_rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn log(hash: &str, level: &str, timestamp: &str) -> LogMessage {
+        LogMessage {
+            level: level.to_string(),
+            severity: None,
+            message: format!("{} at {}", level, timestamp),
+            target: "test".to_string(),
+            module_path: None,
+            file: None,
+            line: None,
+            hash: hash.to_string(),
+            timestamp: timestamp.to_string(),
+            fields: None,
+            peer: None,
+            session_id: None,
+        }
+    }
+
+    #[test]
+    fn knows_stored_hashes_once_counted() {
+        let mut counts = LogCounts::new();
+        assert_eq!(counts.stored_hashes(), None);
+
+        counts.stale = false;
+        counts.add(&[log("a", "INFO", "t"), log("a", "WARN", "t"), log("b", "INFO", "t")]);
+        counts.subtract("b", &Some("INFO".to_string()), 1);
+        assert_eq!(counts.stored_hashes(), Some(HashSet::from(["a".to_string()])));
+
+        counts.invalidate();
+        assert_eq!(counts.stored_hashes(), None);
+    }
+
+    async fn memory_pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        crate::schema::migrate(&pool, &[]).await;
+        pool
+    }
+
+    async fn store(pool: &SqlitePool, table: &str, logs: &[LogMessage]) {
+        for log in logs {
+            sqlx::query(&format!("INSERT INTO {} (level, message, hash, timestamp) VALUES (?, ?, ?, ?)", table))
+                .bind(&log.level)
+                .bind(&log.message)
+                .bind(&log.hash)
+                .bind(&log.timestamp)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    fn ago(duration: chrono::Duration) -> String {
+        (Utc::now() - duration).to_rfc3339()
+    }
+
+    fn rules(json: serde_json::Value) -> Vec<RetentionRule> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    /// Run one sweep, returning its report and the counts it left behind.
+    async fn run_sweep(pool: &SqlitePool, rules: &[RetentionRule], args: &[&str]) -> (SweepReport, LogCounts) {
+        let config = Config::parse_from(std::iter::once("ironlog").chain(args.iter().copied()));
+        let counts = Mutex::new(LogCounts::new());
+        let metrics = Mutex::new(SweepMetrics::default());
+        sweep(pool, rules, &config, &counts, &metrics).await;
+        let report = metrics.into_inner().unwrap().last.expect("the sweep ran");
+        (report, counts.into_inner().unwrap())
+    }
+
+    /// The counts the sweep kept up to date must agree with counting the table again.
+    async fn assert_counts_match(pool: &SqlitePool, counts: &LogCounts) {
+        let reloaded = Mutex::new(LogCounts::new());
+        reload_counts(pool, &reloaded).await.unwrap();
+        let without_empty = |counts: &LogCounts| {
+            let mut snapshot = counts.snapshot();
+            snapshot.values_mut().for_each(|levels| {
+                levels.retain(|(_, count)| *count > 0);
+                levels.sort();
+            });
+            snapshot.retain(|_, levels| !levels.is_empty());
+            snapshot
+        };
+        assert_eq!(without_empty(counts), without_empty(&reloaded.into_inner().unwrap()));
+    }
+
+    async fn messages(pool: &SqlitePool, table: &str) -> Vec<String> {
+        sqlx::query_scalar(&format!("SELECT message FROM {} ORDER BY id", table)).fetch_all(pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn expires_logs_by_the_period_of_their_hash_and_level() {
+        let pool = memory_pool().await;
+        let two_hours = ago(chrono::Duration::hours(2));
+        let two_days = ago(chrono::Duration::days(2));
+        let thirty_days = ago(chrono::Duration::days(30));
+        store(&pool, "logs", &[
+            log("app", "INFO", &two_hours),
+            log("app", "INFO", &two_days),
+            log("app", "ERROR", &thirty_days),
+            log("edge-1", "DEBUG", &two_hours),
+            log("edge-1", "INFO", &two_hours),
+        ])
+        .await;
+        sqlx::query("INSERT INTO logs (message, hash, timestamp) VALUES ('no level', 'app', ?)")
+            .bind(&two_days)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let rules = rules(serde_json::json!([
+            { "hash": "edge-*", "level": "DEBUG", "max_age": "1h" },
+            { "level": "ERROR", "max_age": "90d" },
+        ]));
+        let (report, counts) = run_sweep(&pool, &rules, &["--retention", "1d"]).await;
+
+        assert_eq!(report.expired, 3);
+        assert_eq!(
+            messages(&pool, "logs").await,
+            [format!("INFO at {}", two_hours), format!("ERROR at {}", thirty_days), format!("INFO at {}", two_hours)]
+        );
+        assert_counts_match(&pool, &counts).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_logs_of_any_age_without_a_retention() {
+        let pool = memory_pool().await;
+        store(&pool, "logs", &[log("app", "INFO", &ago(chrono::Duration::days(3650)))]).await;
+        let (report, _) = run_sweep(&pool, &[], &[]).await;
+        assert_eq!((report.expired, report.chunks), (0, 0));
+        assert_eq!(messages(&pool, "logs").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn trims_hashes_and_levels_to_their_newest_logs() {
+        let pool = memory_pool().await;
+        let at = |minute: u32| format!("2024-01-01T00:{:02}:00+00:00", minute);
+        // Stored out of order, so only the timestamps tell which are newest
+        let app: Vec<LogMessage> = [3, 1, 5, 2, 4].iter().map(|minute| log("app", "INFO", &at(*minute))).collect();
+        store(&pool, "logs", &app).await;
+        let gateway: Vec<LogMessage> = [1, 2, 3].iter().map(|minute| log("gateway", "DEBUG", &at(*minute))).collect();
+        store(&pool, "logs", &gateway).await;
+        store(&pool, "logs", &[log("gateway", "INFO", &at(4)), log("gateway", "INFO", &at(5))]).await;
+
+        let rules = rules(serde_json::json!([
+            { "hash": "gateway", "level": "DEBUG", "max_log_count": 1 },
+        ]));
+        let (report, counts) = run_sweep(&pool, &rules, &["--max-log-count", "3"]).await;
+
+        assert_eq!(report.trimmed, 4);
+        let app_left: Vec<String> = sqlx::query_scalar("SELECT timestamp FROM logs WHERE hash = 'app' ORDER BY timestamp")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(app_left, [at(3), at(4), at(5)]);
+        let gateway_left: Vec<String> = sqlx::query_scalar("SELECT timestamp FROM logs WHERE hash = 'gateway' ORDER BY timestamp")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(gateway_left, [at(3), at(4), at(5)]);
+        assert_counts_match(&pool, &counts).await;
+    }
+
+    #[tokio::test]
+    async fn deletes_in_chunks() {
+        let pool = memory_pool().await;
+        let rows = 2 * SWEEP_CHUNK + 1000;
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+            INSERT INTO logs (level, message, hash, timestamp)
+            SELECT 'INFO', i, 'app', printf('2024-01-01T00:00:00.%06d+00:00', i) FROM n",
+        )
+        .bind(rows as i64)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (report, counts) = run_sweep(&pool, &[], &["--max-log-count", "1000"]).await;
+
+        assert_eq!(report.trimmed, rows - 1000);
+        assert_eq!(report.chunks, 3);
+        let oldest_left: i64 = sqlx::query_scalar("SELECT MIN(CAST(message AS INTEGER)) FROM logs").fetch_one(&pool).await.unwrap();
+        assert_eq!(oldest_left, (rows - 1000 + 1) as i64);
+        assert_counts_match(&pool, &counts).await;
+    }
+
+    #[tokio::test]
+    async fn fits_the_size_budget_deleting_archived_logs_first() {
+        let pool = memory_pool().await;
+        let padded = |hash: &str, n: usize| LogMessage { message: format!("{:0>500}", n), ..log(hash, "INFO", "2024-01-01T00:00:00+00:00") };
+        let archived: Vec<LogMessage> = (0..1000).map(|n| padded("gone", n)).collect();
+        store(&pool, "archived_logs", &archived).await;
+        let live: Vec<LogMessage> = (0..1000).map(|n| padded("app", n)).collect();
+        store(&pool, "logs", &live).await;
+        let used = PageUsage::read(&pool).await.unwrap().used_bytes();
+
+        // Room for everything but some of the archive
+        let budget = format!("{}K", used * 3 / 4 / 1024);
+        let (report, _) = run_sweep(&pool, &[], &["--max-log-count", "5000", "--max-db-size", &budget]).await;
+        assert!(report.archived_over_size > 0);
+        assert_eq!(report.over_size, 0);
+        assert_eq!(messages(&pool, "logs").await.len(), 1000);
+        assert!(PageUsage::read(&pool).await.unwrap().used_bytes() <= used * 3 / 4);
+
+        // Room for only some of the live logs, which go oldest first once the archive is empty
+        let budget = format!("{}K", used / 4 / 1024);
+        let (report, counts) = run_sweep(&pool, &[], &["--max-log-count", "5000", "--max-db-size", &budget]).await;
+        assert!(report.over_size > 0);
+        assert!(messages(&pool, "archived_logs").await.is_empty());
+        let left = messages(&pool, "logs").await;
+        assert_eq!(left.len() as u64, 1000 - report.over_size);
+        assert_eq!(left.last(), Some(&format!("{:0>500}", 999)));
+        assert_eq!(left.first(), Some(&format!("{:0>500}", report.over_size)));
+        assert!(PageUsage::read(&pool).await.unwrap().used_bytes() <= used / 4);
+        assert_counts_match(&pool, &counts).await;
+    }
+}
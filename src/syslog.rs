@@ -0,0 +1,484 @@
+// syslog.rs
+
+//! Syslog ingestion over UDP and TCP.
+//!
+//! Both RFC 5424 and the older BSD format from RFC 3164 are understood. TCP connections may
+//! use either newline-delimited or octet-counted framing (RFC 6587).
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::types::Json;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::client_handler::{
+    read_bounded_line, skip_line, with_idle_timeout, Ingest, LineRead, Peer, ReadRateLimiter, ShuttingDown,
+    OVERSIZED_SAMPLE_LENGTH,
+};
+use crate::levels::syslog_level;
+use crate::types::{Fields, LogMessage};
+
+/// Largest syslog message accepted on either transport.
+const MAX_SYSLOG_MESSAGE: usize = 64 * 1024;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp",
+    "ntp", "security", "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyslogMessage {
+    pub facility: u8,
+    pub severity: u8,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub hostname: Option<String>,
+    pub app_name: Option<String>,
+    pub procid: Option<String>,
+    pub msgid: Option<String>,
+    /// SD-ID to its parameters, e.g. `{"exampleSDID@32473": {"iut": "3"}}`.
+    pub structured_data: Fields,
+    pub message: String,
+}
+
+/// Parse one syslog message in either RFC 5424 or RFC 3164 format.
+pub fn parse(line: &str) -> Option<SyslogMessage> {
+    let line = line.trim_end_matches(['\r', '\n', '\0']);
+    let rest = line.strip_prefix('<')?;
+    let (pri, rest) = rest.split_once('>')?;
+    let pri: u8 = pri.parse().ok().filter(|pri| *pri <= 191)?;
+
+    let mut message = SyslogMessage {
+        facility: pri / 8,
+        severity: pri % 8,
+        timestamp: None,
+        hostname: None,
+        app_name: None,
+        procid: None,
+        msgid: None,
+        structured_data: Fields::new(),
+        message: String::new(),
+    };
+
+    // RFC 5424 puts a version number right after the priority
+    let is_rfc5424 = rest
+        .split_once(' ')
+        .is_some_and(|(version, _)| !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit()));
+
+    if is_rfc5424 {
+        parse_rfc5424(rest, &mut message)?;
+    } else {
+        parse_rfc3164(rest, &mut message);
+    }
+    Some(message)
+}
+
+fn nil(value: &str) -> Option<String> {
+    (value != "-").then(|| value.to_string())
+}
+
+fn parse_rfc5424(rest: &str, message: &mut SyslogMessage) -> Option<()> {
+    let mut parts = rest.splitn(7, ' ');
+    let _version = parts.next()?;
+    let timestamp = parts.next()?;
+    message.timestamp = DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc));
+    message.hostname = nil(parts.next()?);
+    message.app_name = nil(parts.next()?);
+    message.procid = nil(parts.next()?);
+    message.msgid = nil(parts.next()?);
+
+    let rest = parts.next().unwrap_or("");
+    let rest = if let Some(rest) = rest.strip_prefix('-') {
+        rest
+    } else {
+        let (structured_data, rest) = parse_structured_data(rest)?;
+        message.structured_data = structured_data;
+        rest
+    };
+    let msg = rest.strip_prefix(' ').unwrap_or(rest);
+    message.message = msg.strip_prefix('\u{feff}').unwrap_or(msg).to_string();
+    Some(())
+}
+
+/// Parse `[id name="value" ...][id2 ...]`, returning the elements and the remaining input.
+fn parse_structured_data(input: &str) -> Option<(Fields, &str)> {
+    let mut elements = Fields::new();
+    let mut rest = input;
+
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element.find([' ', ']'])?;
+        let id = &element[..id_end];
+        let mut params = Fields::new();
+        rest = &element[id_end..];
+
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if let Some(after) = rest.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+            let (name, after) = rest.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()? {
+                        (_, c @ ('"' | '\\' | ']')) => value.push(c),
+                        (_, c) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                    },
+                    (index, '"') => break index,
+                    (_, c) => value.push(c),
+                }
+            };
+            params.insert(name.to_string(), Value::String(value));
+            rest = &after[end + 1..];
+        }
+
+        elements.insert(id.to_string(), Value::Object(params));
+    }
+
+    Some((elements, rest))
+}
+
+/// `Mmm dd hh:mm:ss` carries no year, so take the one that puts it closest to `now`: a message
+/// from December 31st received on January 1st belongs to the year before.
+fn rfc3164_timestamp(timestamp: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    [now.year() - 1, now.year(), now.year() + 1]
+        .into_iter()
+        .filter_map(|year| NaiveDateTime::parse_from_str(&format!("{} {}", year, timestamp), "%Y %b %e %H:%M:%S").ok())
+        .map(|timestamp| timestamp.and_utc())
+        .min_by_key(|timestamp| (*timestamp - now).num_seconds().abs())
+}
+
+fn parse_rfc3164(rest: &str, message: &mut SyslogMessage) {
+    let mut rest = rest;
+    if let Some(timestamp) = rest.get(..15) {
+        if let Some(timestamp) = rfc3164_timestamp(timestamp, Utc::now()) {
+            message.timestamp = Some(timestamp);
+            rest = rest[15..].trim_start_matches(' ');
+
+            // The hostname is optional; a token ending in ':' or containing '[' is already the tag
+            if let Some((hostname, after)) = rest.split_once(' ') {
+                if !hostname.ends_with(':') && !hostname.contains('[') {
+                    message.hostname = Some(hostname.to_string());
+                    rest = after;
+                }
+            }
+        }
+    }
+
+    // TAG[pid]: message
+    if let Some((tag, msg)) = rest.split_once(": ") {
+        let (app_name, procid) = match tag.split_once('[') {
+            Some((app_name, pid)) => (app_name, pid.strip_suffix(']')),
+            None => (tag, None),
+        };
+        if !app_name.is_empty() && !app_name.contains(' ') {
+            message.app_name = Some(app_name.to_string());
+            message.procid = procid.map(str::to_string);
+            rest = msg;
+        }
+    }
+    message.message = rest.to_string();
+}
+
+pub fn facility_name(facility: u8) -> &'static str {
+    FACILITIES.get(facility as usize).copied().unwrap_or("unknown")
+}
+
+impl SyslogMessage {
+    /// Convert into a `LogMessage`, deriving the hash from `hash_template`.
+    ///
+    /// `{hostname}`, `{app}` and `{facility}` are substituted in the template. Messages without
+    /// a hostname use the sender's address instead.
+    pub fn into_log_message(self, hash_template: &str, peer: IpAddr) -> LogMessage {
+        let hostname = self.hostname.clone().unwrap_or_else(|| peer.to_string());
+        let app_name = self.app_name.clone().unwrap_or_else(|| "syslog".to_string());
+        let facility = facility_name(self.facility);
+
+        let hash = hash_template
+            .replace("{hostname}", &hostname)
+            .replace("{app}", &app_name)
+            .replace("{facility}", facility);
+
+        let mut fields = Fields::new();
+        fields.insert("facility".to_string(), json!(facility));
+        fields.insert("severity".to_string(), json!(self.severity));
+        fields.insert("hostname".to_string(), json!(hostname));
+        if let Some(procid) = self.procid {
+            fields.insert("procid".to_string(), json!(procid));
+        }
+        if let Some(msgid) = self.msgid {
+            fields.insert("msgid".to_string(), json!(msgid));
+        }
+        if !self.structured_data.is_empty() {
+            fields.insert("structured_data".to_string(), Value::Object(self.structured_data));
+        }
+
+        LogMessage {
+            level: syslog_level(self.severity).to_string(),
+            severity: None,
+            message: self.message,
+            target: app_name,
+            module_path: None,
+            file: None,
+            line: None,
+            hash,
+            timestamp: self.timestamp.unwrap_or_else(Utc::now).to_rfc3339(),
+            fields: Some(Json(fields)),
+            peer: None,
+            session_id: None,
+        }
+    }
+}
+
+/// Bind the UDP and TCP syslog listeners on `listener_addr` and feed them into `ingest`.
+pub(crate) async fn start_syslog_listeners(listener_addr: &str, ingest: Ingest) {
+    let udp_socket = UdpSocket::bind(listener_addr).await.expect("Failed to bind syslog UDP socket");
+    let tcp_listener = TcpListener::bind(listener_addr).await.expect("Failed to bind syslog TCP listener");
+    println!("Syslog server is running on {} (UDP and TCP)", listener_addr);
+
+    let udp_ingest = ingest.clone();
+    tokio::spawn(async move {
+        receive_udp(udp_socket, udp_ingest).await;
+    });
+
+    tokio::spawn(async move {
+        while let Some((socket, peer, guard)) = ingest.accept(&tcp_listener).await {
+            ingest.spawn_connection(guard, handle_tcp_client(socket, peer, ingest.clone()));
+        }
+    });
+}
+
+async fn receive_udp(socket: UdpSocket, ingest: Ingest) {
+    let mut buf = vec![0; MAX_SYSLOG_MESSAGE];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = ingest.draining() => break,
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Failed to receive syslog datagram: {}", e);
+                continue;
+            }
+        };
+        if ingest.is_permitted(peer.ip()) {
+            let _ = submit(&buf[..len], peer, &Peer::new(peer.ip()), &ingest).await;
+        }
+    }
+}
+
+async fn handle_tcp_client(socket: TcpStream, peer: SocketAddr, ingest: Ingest) {
+    let idle_timeout = ingest.config().idle_timeout.map(Duration::from_secs);
+    let mut rate_limiter = ingest.config().max_read_rate.map(ReadRateLimiter::new);
+    let mut reader = BufReader::new(socket);
+    let mut frame = Vec::new();
+    let session = Peer::with_session(peer.ip());
+    loop {
+        let read = match with_idle_timeout(idle_timeout, read_tcp_frame(&mut reader, &mut frame)).await {
+            Ok(LineRead::Line) => frame.len(),
+            Ok(LineRead::TooLong) => {
+                let read = frame.len();
+                frame.truncate(OVERSIZED_SAMPLE_LENGTH);
+                let error = format!("message longer than {} bytes", MAX_SYSLOG_MESSAGE);
+                ingest.reject("syslog", &frame, error, peer).await;
+                // The rest of the line is not a message of its own
+                let Ok(skipped) = with_idle_timeout(idle_timeout, skip_line(&mut reader)).await else { break };
+                if let Some(rate_limiter) = rate_limiter.as_mut() {
+                    rate_limiter.consume(read + skipped as usize).await;
+                }
+                continue;
+            }
+            Ok(LineRead::Eof) => break,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    println!("Closing idle connection from {}", peer);
+                }
+                break;
+            }
+        };
+        if submit(&frame, peer, &session, &ingest).await.is_err() {
+            break;
+        }
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            rate_limiter.consume(read).await;
+        }
+    }
+}
+
+/// Read one octet-counted (`123 <34>1 ...`) or newline-terminated frame into `frame`.
+///
+/// A newline-terminated frame longer than `MAX_SYSLOG_MESSAGE` is `TooLong`, with its first
+/// bytes in `frame` and the rest left unread.
+async fn read_tcp_frame<R: AsyncBufRead + Unpin>(reader: &mut R, frame: &mut Vec<u8>) -> std::io::Result<LineRead> {
+    frame.clear();
+    let first = match reader.fill_buf().await?.first() {
+        Some(first) => *first,
+        None => return Ok(LineRead::Eof),
+    };
+
+    if first.is_ascii_digit() {
+        // The longest valid count is 5 digits, so don't read far looking for the space
+        (&mut *reader).take(10).read_until(b' ', frame).await?;
+        let length: usize = frame
+            .strip_suffix(b" ")
+            .filter(|digits| digits.iter().all(u8::is_ascii_digit))
+            .and_then(|digits| std::str::from_utf8(digits).ok()?.parse().ok())
+            .filter(|length| *length <= MAX_SYSLOG_MESSAGE)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "bad syslog frame length"))?;
+        frame.resize(length, 0);
+        reader.read_exact(frame).await?;
+        Ok(LineRead::Line)
+    } else {
+        read_bounded_line(reader, frame, MAX_SYSLOG_MESSAGE).await
+    }
+}
+
+async fn submit(frame: &[u8], peer: SocketAddr, session: &Peer, ingest: &Ingest) -> Result<(), ShuttingDown> {
+    let line = String::from_utf8_lossy(frame);
+    match parse(&line) {
+        Some(message) => {
+            let log_message = message.into_log_message(&ingest.config().syslog_hash, peer.ip());
+            ingest.submit(vec![log_message], session).await
+        }
+        None => {
+            ingest.reject("syslog", frame, "not a syslog message", peer).await;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rfc5424() {
+        let message = parse(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+             [exampleSDID@32473 iut=\"3\" eventSource=\"Application\"] An application event",
+        )
+        .unwrap();
+        assert_eq!((message.facility, message.severity), (20, 5));
+        assert_eq!(message.timestamp.unwrap().to_rfc3339(), "2003-10-11T22:14:15.003+00:00");
+        assert_eq!(message.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(message.app_name.as_deref(), Some("evntslog"));
+        assert_eq!(message.procid, None);
+        assert_eq!(message.msgid.as_deref(), Some("ID47"));
+        assert_eq!(message.structured_data["exampleSDID@32473"]["iut"], "3");
+        assert_eq!(message.structured_data["exampleSDID@32473"]["eventSource"], "Application");
+        assert_eq!(message.message, "An application event");
+    }
+
+    #[test]
+    fn parses_rfc5424_without_structured_data() {
+        let message = parse("<34>1 2003-10-11T22:14:15+02:00 host su 123 - - \u{feff}'su root' failed\n").unwrap();
+        assert_eq!(message.timestamp.unwrap().to_rfc3339(), "2003-10-11T20:14:15+00:00");
+        assert_eq!(message.procid.as_deref(), Some("123"));
+        assert!(message.structured_data.is_empty());
+        assert_eq!(message.message, "'su root' failed");
+    }
+
+    #[test]
+    fn unescapes_structured_data_values() {
+        let message = parse(r#"<14>1 - - app - - [meta note="a \"quoted\" \] value"] text"#).unwrap();
+        assert_eq!(message.structured_data["meta"]["note"], r#"a "quoted" ] value"#);
+        assert_eq!(message.message, "text");
+        assert!(parse(r#"<14>1 - - app - - [meta note="unterminated] text"#).is_none());
+    }
+
+    #[test]
+    fn parses_rfc3164() {
+        let message = parse("<34>Oct 11 22:14:15 mymachine su[42]: 'su root' failed for lonvick").unwrap();
+        assert_eq!((message.facility, message.severity), (4, 2));
+        assert_eq!(message.timestamp.unwrap().format("%m-%d %H:%M:%S").to_string(), "10-11 22:14:15");
+        assert_eq!(message.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(message.app_name.as_deref(), Some("su"));
+        assert_eq!(message.procid.as_deref(), Some("42"));
+        assert_eq!(message.message, "'su root' failed for lonvick");
+    }
+
+    #[test]
+    fn parses_rfc3164_without_hostname_or_timestamp() {
+        let message = parse("<13>Feb  5 17:32:18 cron: job done").unwrap();
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.app_name.as_deref(), Some("cron"));
+        assert_eq!(message.message, "job done");
+
+        let message = parse("<13>just some text").unwrap();
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.app_name, None);
+        assert_eq!(message.message, "just some text");
+    }
+
+    fn read_frames(mut input: &[u8]) -> std::io::Result<Vec<String>> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut frames = Vec::new();
+            let mut frame = Vec::new();
+            loop {
+                match read_tcp_frame(&mut input, &mut frame).await? {
+                    LineRead::Line => frames.push(String::from_utf8_lossy(&frame).into_owned()),
+                    LineRead::TooLong => {
+                        frames.push(format!("too long: {} bytes", frame.len()));
+                        skip_line(&mut input).await?;
+                    }
+                    LineRead::Eof => return Ok(frames),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn reads_octet_counted_and_newline_frames() {
+        let frames = read_frames(b"11 <13>counted<13>line\n<13>last").unwrap();
+        assert_eq!(frames, ["<13>counted", "<13>line", "<13>last"]);
+    }
+
+    #[test]
+    fn cuts_overlong_lines_without_splitting_them() {
+        let mut input = b"<13>".to_vec();
+        input.extend(std::iter::repeat_n(b'x', MAX_SYSLOG_MESSAGE + 100));
+        input.extend_from_slice(b"\n<13>next\n");
+        let frames = read_frames(&input).unwrap();
+        assert_eq!(frames, [format!("too long: {} bytes", MAX_SYSLOG_MESSAGE), "<13>next".to_string()]);
+    }
+
+    #[test]
+    fn dates_rfc3164_timestamps_in_the_closest_year() {
+        let at = |timestamp: &str| DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc);
+        let new_year = at("2025-01-01T00:00:10Z");
+        assert_eq!(rfc3164_timestamp("Dec 31 23:59:59", new_year), Some(at("2024-12-31T23:59:59Z")));
+        assert_eq!(rfc3164_timestamp("Jan  1 00:00:05", new_year), Some(at("2025-01-01T00:00:05Z")));
+        let new_years_eve = at("2024-12-31T23:59:00Z");
+        assert_eq!(rfc3164_timestamp("Jan  1 00:00:30", new_years_eve), Some(at("2025-01-01T00:00:30Z")));
+        // Only leap years have a February 29th
+        assert_eq!(rfc3164_timestamp("Feb 29 12:00:00", at("2025-03-01T00:00:00Z")), Some(at("2024-02-29T12:00:00Z")));
+        assert_eq!(rfc3164_timestamp("Foo 12 12:00:00", new_year), None);
+    }
+
+    #[test]
+    fn rejects_bad_octet_counts() {
+        assert!(read_frames(b"12x4 <13>hello").is_err());
+        assert!(read_frames(b"99999999999999999999 <13>hello").is_err());
+        assert!(read_frames(b"65537 <13>hello").is_err());
+        assert!(read_frames(b"11").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_priority() {
+        assert!(parse("no priority").is_none());
+        assert!(parse("<192>1 - - - - - -").is_none());
+        assert!(parse("<abc>text").is_none());
+    }
+}
@@ -0,0 +1,244 @@
+// testing.rs
+
+//! In-process log server for asserting on logs emitted through [`TcpLogger`](crate::TcpLogger).
+//!
+//! ```no_run
+//! use ironlog::testing::MockServer;
+//! use ironlog::TcpLogger;
+//!
+//! let server = MockServer::start().unwrap();
+//! let logger = TcpLogger::new(&server.addr().to_string(), "my-test", false).unwrap();
+//! logger.error("boom");
+//!
+//! let log = server.wait_for(|m| m.level == "ERROR").expect("no error log received");
+//! assert_eq!(log.message, "boom");
+//! ```
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{
+    decode_json_records, decode_msgpack_records, parse_client_hello, ClientHello, Framing, HelloFrame,
+    ServerHello, CAP_MSGPACK, MAX_FRAME_LENGTH,
+};
+use crate::types::LogMessage;
+
+/// How long [`MockServer::wait_for`] waits before giving up.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Received {
+    logs: Mutex<Vec<LogMessage>>,
+    hellos: Mutex<Vec<ClientHello>>,
+    arrived: Condvar,
+}
+
+/// A log server bound to an ephemeral port on localhost.
+///
+/// Every line a client sends is decoded as a [`LogMessage`] and kept in memory, and hello
+/// frames are answered like the real server would. Nothing is written to disk. The server
+/// stops accepting connections when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    received: Arc<Received>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    pub fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let received = Arc::new(Received::default());
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let accept_received = Arc::clone(&received);
+        let accept_shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                if accept_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(socket) = socket {
+                    let received = Arc::clone(&accept_received);
+                    thread::spawn(move || read_client(socket, &received));
+                }
+            }
+        });
+
+        Ok(MockServer {
+            addr,
+            received,
+            shutdown,
+        })
+    }
+
+    /// Address to hand to `TcpLogger::new` or `TcpLogger::init`.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// All logs received so far, in arrival order.
+    pub fn received(&self) -> Vec<LogMessage> {
+        self.received.logs.lock().unwrap().clone()
+    }
+
+    /// Hello frames received so far, in arrival order.
+    pub fn hellos(&self) -> Vec<ClientHello> {
+        self.received.hellos.lock().unwrap().clone()
+    }
+
+    /// Forget everything received so far.
+    pub fn clear(&self) {
+        self.received.logs.lock().unwrap().clear();
+        self.received.hellos.lock().unwrap().clear();
+    }
+
+    /// Wait up to [`DEFAULT_WAIT_TIMEOUT`] for a log matching `predicate`.
+    pub fn wait_for<F>(&self, predicate: F) -> Option<LogMessage>
+    where
+        F: Fn(&LogMessage) -> bool,
+    {
+        self.wait_for_timeout(DEFAULT_WAIT_TIMEOUT, predicate)
+    }
+
+    /// Wait up to `timeout` for a log matching `predicate`, including ones that already arrived.
+    pub fn wait_for_timeout<F>(&self, timeout: Duration, predicate: F) -> Option<LogMessage>
+    where
+        F: Fn(&LogMessage) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut logs = self.received.logs.lock().unwrap();
+        loop {
+            if let Some(log) = logs.iter().find(|log| predicate(log)) {
+                return Some(log.clone());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            logs = self.received.arrived.wait_timeout(logs, remaining).unwrap().0;
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the shutdown flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn read_client(socket: TcpStream, received: &Received) {
+    let Ok(mut writer) = socket.try_clone() else { return };
+    let mut reader = BufReader::new(socket);
+    let mut framing = Framing::JsonLines;
+    let mut line = String::new();
+    let mut first_frame = true;
+
+    loop {
+        let log_messages = match framing {
+            Framing::JsonLines => {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = line.trim_end_matches(['\r', '\n']);
+
+                if std::mem::take(&mut first_frame) {
+                    if let Some(hello) = parse_client_hello(line) {
+                        let reply = HelloFrame {
+                            hello: ServerHello::reply_to(&hello, usize::MAX, MAX_FRAME_LENGTH),
+                        };
+                        received.hellos.lock().unwrap().push(hello);
+                        let Ok(reply_json) = serde_json::to_string(&reply) else { break };
+                        if writeln!(writer, "{}", reply_json).is_err() {
+                            break;
+                        }
+                        if reply.hello.has_capability(CAP_MSGPACK) {
+                            framing = Framing::MsgPack;
+                        }
+                        continue;
+                    }
+                }
+
+                decode_json_records(line).ok()
+            }
+            Framing::MsgPack => {
+                let mut length = [0; 4];
+                if reader.read_exact(&mut length).is_err() {
+                    break;
+                }
+                let length = u32::from_be_bytes(length) as usize;
+                if length > MAX_FRAME_LENGTH {
+                    break;
+                }
+                let mut frame = vec![0; length];
+                if reader.read_exact(&mut frame).is_err() {
+                    break;
+                }
+                decode_msgpack_records(&frame).ok()
+            }
+        };
+
+        if let Some(log_messages) = log_messages {
+            received.logs.lock().unwrap().extend(log_messages);
+            received.arrived.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CAP_BATCH;
+    use crate::TcpLogger;
+
+    #[test]
+    fn receives_json_lines() {
+        let server = MockServer::start().unwrap();
+        let logger = TcpLogger::new(&server.addr().to_string(), "mock-test", false).unwrap();
+        logger.info("hello");
+        logger.error("boom");
+
+        let log = server.wait_for(|log| log.level == "ERROR").expect("no error log received");
+        assert_eq!(log.message, "boom");
+        assert_eq!(log.hash, "mock-test");
+        let messages: Vec<String> = server.received().into_iter().map(|log| log.message).collect();
+        assert_eq!(messages, ["hello", "boom"]);
+    }
+
+    #[test]
+    fn answers_hellos_and_decodes_msgpack_batches() {
+        let server = MockServer::start().unwrap();
+        let logger = TcpLogger::new(&server.addr().to_string(), "mock-batch", false).unwrap();
+        let hello = logger.handshake(&[CAP_BATCH, CAP_MSGPACK]).unwrap();
+        assert!(hello.has_capability(CAP_BATCH) && hello.has_capability(CAP_MSGPACK));
+        assert_eq!(server.hellos()[0].hash.as_deref(), Some("mock-batch"));
+
+        for index in 0..10 {
+            logger.warn(&format!("record {}", index));
+        }
+        log::Log::flush(&logger);
+
+        assert!(server.wait_for(|log| log.message == "record 9").is_some());
+        assert_eq!(server.received().len(), 10);
+    }
+
+    #[test]
+    fn wait_for_gives_up_and_clear_forgets() {
+        let server = MockServer::start().unwrap();
+        let logger = TcpLogger::new(&server.addr().to_string(), "mock-clear", false).unwrap();
+        logger.debug("first");
+        assert!(server.wait_for(|log| log.message == "first").is_some());
+
+        server.clear();
+        assert!(server.received().is_empty());
+        assert!(server.wait_for_timeout(Duration::from_millis(50), |log| log.message == "first").is_none());
+    }
+}
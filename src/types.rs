@@ -1,6 +1,5 @@
 use rocket::http::ContentType;
 use rocket::form::FromForm;
-use rocket::serde::json::Json;
 use include_dir::{include_dir, Dir};
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
@@ -8,10 +7,19 @@ use sqlx::{SqlitePool, sqlite::SqlitePoolOptions, Row};
 use std::fs;
 use std::sync::Arc;
 use chrono;
+use sqlx::types::Json;
 
-#[derive(Serialize, Deserialize, Clone, sqlx::FromRow)]
+/// Structured key/value data attached to a log, stored as JSON in the `fields` column.
+pub type Fields = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow)]
 pub struct LogMessage {
+    #[serde(deserialize_with = "crate::levels::deserialize_level")]
     pub level: String,
+    /// Position of `level` on the canonical scale, filled in by the server.
+    #[serde(default)]
+    #[sqlx(default)]
+    pub severity: Option<i64>,
     pub message: String,
     pub target: String,
     pub module_path: Option<String>,
@@ -20,9 +28,115 @@ pub struct LogMessage {
     pub hash: String,
     #[serde(default = "default_timestamp")]
     pub timestamp: String,
+    #[serde(default)]
+    pub fields: Option<Json<Fields>>,
+    /// Address the record was received from, filled in by the server.
+    #[serde(default, skip_deserializing)]
+    #[sqlx(default)]
+    pub peer: Option<String>,
+    /// Connection the record arrived on, filled in by the server for TCP inputs.
+    #[serde(default, skip_deserializing)]
+    #[sqlx(default)]
+    pub session_id: Option<String>,
 }
 
 // Make sure to define the default_timestamp function
 pub fn default_timestamp() -> String {
     chrono::Utc::now().to_rfc3339()
 }
+
+/// Bring a client's timestamp into the stored form, RFC 3339 in UTC, so timestamps compare
+/// correctly as text. Accepts RFC 3339 with any offset, a date and time without one (taken as
+/// UTC), or a Unix epoch in seconds, milliseconds, microseconds or nanoseconds.
+pub fn normalize_timestamp(timestamp: &str) -> Option<String> {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+
+    let timestamp = timestamp.trim();
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(timestamp) {
+        return Some(parsed.with_timezone(&Utc).to_rfc3339());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(parsed) = NaiveDateTime::parse_from_str(timestamp, format) {
+            return Some(parsed.and_utc().to_rfc3339());
+        }
+    }
+
+    // Epochs are read as decimal text, as nanoseconds don't fit in an f64
+    let (whole, fraction) = timestamp.split_once('.').unwrap_or((timestamp, ""));
+    let is_digits = |text: &str| text.bytes().all(|byte| byte.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+        return None;
+    }
+    let whole: i64 = whole.parse().ok()?;
+    // Units are told apart by size; seconds stay below 1e11 until the year 5138
+    let fraction_digits: u32 = if whole < 100_000_000_000 {
+        9
+    } else if whole < 100_000_000_000_000 {
+        6
+    } else if whole < 100_000_000_000_000_000 {
+        3
+    } else {
+        0
+    };
+    let fraction: String = fraction.chars().chain(std::iter::repeat('0')).take(fraction_digits as usize).collect();
+    let nanos = whole
+        .checked_mul(10_i64.pow(fraction_digits))?
+        .checked_add(fraction.parse().unwrap_or(0))?;
+    Some(DateTime::<Utc>::from_timestamp_nanos(nanos).to_rfc3339())
+}
+
+/// A frame that could not be decoded, kept for debugging broken clients.
+#[derive(Serialize, Clone, Debug, sqlx::FromRow)]
+pub struct RejectedLine {
+    pub line: String,
+    pub error: String,
+    pub peer: String,
+    pub source: String,
+    pub timestamp: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_rfc3339_to_utc() {
+        assert_eq!(normalize_timestamp("2024-03-01T12:00:00Z").as_deref(), Some("2024-03-01T12:00:00+00:00"));
+        assert_eq!(normalize_timestamp("2024-03-01T14:00:00+02:00").as_deref(), Some("2024-03-01T12:00:00+00:00"));
+        assert_eq!(normalize_timestamp(" 2024-03-01T07:00:00.25-05:00 ").as_deref(), Some("2024-03-01T12:00:00.250+00:00"));
+    }
+
+    #[test]
+    fn takes_date_times_without_an_offset_as_utc() {
+        assert_eq!(normalize_timestamp("2024-03-01T12:00:00").as_deref(), Some("2024-03-01T12:00:00+00:00"));
+        assert_eq!(normalize_timestamp("2024-03-01 12:00:00.5").as_deref(), Some("2024-03-01T12:00:00.500+00:00"));
+    }
+
+    #[test]
+    fn tells_epoch_units_apart_by_size() {
+        let expected = Some("2023-11-14T22:13:20+00:00");
+        assert_eq!(normalize_timestamp("1700000000").as_deref(), expected);
+        assert_eq!(normalize_timestamp("1700000000000").as_deref(), expected);
+        assert_eq!(normalize_timestamp("1700000000000000").as_deref(), expected);
+        assert_eq!(normalize_timestamp("1700000000000000000").as_deref(), expected);
+        assert_eq!(normalize_timestamp("1700000000.123456789").as_deref(), Some("2023-11-14T22:13:20.123456789+00:00"));
+        assert_eq!(normalize_timestamp("1700000000123.5").as_deref(), Some("2023-11-14T22:13:20.123500+00:00"));
+    }
+
+    #[test]
+    fn refuses_anything_else() {
+        for timestamp in ["", "yesterday", "2024-13-01T00:00:00Z", "-1700000000", "17.00.00", "17e8", "99999999999999999999"] {
+            assert_eq!(normalize_timestamp(timestamp), None, "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn sorts_like_the_time_it_stands_for() {
+        // As written, the earlier one sorts last
+        let earlier = "2024-03-02T01:00:00+05:00";
+        let later = "2024-03-01T21:00:00Z";
+        assert!(earlier > later);
+        assert!(normalize_timestamp(earlier).unwrap() < normalize_timestamp(later).unwrap());
+        assert!(normalize_timestamp("2024-03-01T12:00:00Z").unwrap() < normalize_timestamp("2024-03-01T12:00:00.5Z").unwrap());
+    }
+}
@@ -0,0 +1,767 @@
+// writer.rs
+
+//! The database writer, which turns queued records into rows without ever giving up on them
+//! silently.
+//!
+//! - Transient errors (`SQLITE_BUSY`, a locked table, an exhausted pool) are retried with
+//!   exponential backoff.
+//! - A batch the database refuses for any other reason is retried one row at a time, and
+//!   the rows that still fail go to the rejected lines table.
+//! - While the database can't be written at all, e.g. because the disk is full, records are
+//!   held in memory (up to `--max-buffered-logs`) and written once it recovers.
+//!
+//! Records are written in batches of `--flush-batch-size`, or once the oldest waiting record is
+//! `--flush-interval-ms` old, using multi-row `INSERT`s. The writer's state is shared through
+//! `WriterHealth` so the API can report it.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config::{Config, HashEviction};
+use crate::shutdown::{recv_until_closed, ShutdownSignal};
+use crate::sweep::LogCounts;
+use crate::types::{LogMessage, RejectedLine};
+
+const INSERT_COLUMNS: &str = "level, severity, message, target, module_path, file, line, hash, timestamp, fields, peer, session_id";
+
+/// Rows per `INSERT` statement, well below SQLite's limit of 32766 bound parameters.
+const ROWS_PER_STATEMENT: usize = 256;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How often writing is attempted again while the database is unavailable.
+const UNAVAILABLE_RETRY: Duration = Duration::from_secs(5);
+
+/// Consecutive failed attempts after which retrying counts as degraded.
+const DEGRADED_AFTER: u64 = 5;
+
+/// What the writer is currently able to do, as reported by `/api/health`.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct WriterHealth {
+    /// `ok`, `retrying` or `degraded`
+    pub status: &'static str,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+    pub last_write_at: Option<String>,
+    /// Records stored since the server started
+    pub written: u64,
+    /// Records stored per second over the last maintenance interval
+    pub ingest_rate: f64,
+    /// Records held in memory until the database accepts them again
+    pub buffered: usize,
+    /// Records lost because the memory buffer was full
+    pub dropped: u64,
+    /// Rows the database refused, moved to the rejected lines table
+    pub isolated: u64,
+    pub retries: u64,
+    #[serde(skip)]
+    consecutive_failures: u64,
+}
+
+impl WriterHealth {
+    pub(crate) fn new() -> Self {
+        WriterHealth {
+            status: "ok",
+            ..Default::default()
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.status == "degraded"
+    }
+
+    /// Set `ingest_rate` from the records written since `previous`, the count taken at an
+    /// earlier sample, and take a new sample.
+    pub(crate) fn sample_ingest_rate(&mut self, previous: &mut (Instant, u64)) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(previous.0).as_secs_f64();
+        if elapsed < 1.0 {
+            return;
+        }
+        self.ingest_rate = self.written.saturating_sub(previous.1) as f64 / elapsed;
+        *previous = (now, self.written);
+    }
+}
+
+/// How a failed write should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// Another connection holds a lock; retry shortly
+    Transient,
+    /// The database can't be written at all (disk full, I/O error, read-only file, ...)
+    Unavailable,
+    /// Something about the records themselves
+    BadRows,
+}
+
+fn classify(error: &sqlx::Error) -> Failure {
+    match error {
+        sqlx::Error::PoolTimedOut => Failure::Transient,
+        sqlx::Error::Database(e) => {
+            // SQLite reports extended result codes; the primary code is the low byte
+            let code = e.code().and_then(|code| code.parse::<i32>().ok()).unwrap_or(0) & 0xff;
+            match code {
+                // SQLITE_BUSY, SQLITE_LOCKED
+                5 | 6 => Failure::Transient,
+                // SQLITE_TOOBIG, SQLITE_CONSTRAINT, SQLITE_MISMATCH, SQLITE_RANGE
+                18 | 19 | 20 | 25 => Failure::BadRows,
+                _ => Failure::Unavailable,
+            }
+        }
+        _ => Failure::Unavailable,
+    }
+}
+
+/// Work for the database writer, done in the order it was queued.
+// Nearly everything queued is a `Log`, so boxing it would only cost an allocation per record
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Queued {
+    Log(LogMessage),
+    /// Remove the rows of a hash evicted under `--hash-eviction`, once everything queued
+    /// before it is stored
+    Evict(String),
+}
+
+/// Write records from `log_receiver` until the channel is closed at shutdown.
+pub(crate) async fn database_writer(
+    mut log_receiver: mpsc::Receiver<Queued>,
+    db_pool: SqlitePool,
+    health: Arc<Mutex<WriterHealth>>,
+    counts: Arc<Mutex<LogCounts>>,
+    rejected_sender: mpsc::Sender<RejectedLine>,
+    config: Arc<Config>,
+    shutdown: ShutdownSignal,
+) {
+    let batch_size = config.flush_batch_size.max(1);
+    let mut writer = Writer {
+        pending: VecDeque::with_capacity(batch_size),
+        front: 0,
+        oldest: None,
+        retry_at: None,
+        backoff: INITIAL_BACKOFF,
+        batch_size,
+        flush_interval: Duration::from_millis(config.flush_interval_ms),
+        max_buffered: config.max_buffered_logs,
+        evictions: VecDeque::new(),
+        archive: config.hash_eviction == HashEviction::Archive,
+        db_pool,
+        health,
+        counts,
+        rejected_sender,
+    };
+    let mut closed = false;
+
+    loop {
+        // `None` when it's time to write before another record arrived
+        let received = match writer.write_due() {
+            Some(due) => tokio::select! {
+                received = recv_until_closed(&mut log_receiver, &shutdown, &mut closed) => Some(received),
+                _ = sleep_until(due) => None,
+            },
+            None => Some(recv_until_closed(&mut log_receiver, &shutdown, &mut closed).await),
+        };
+
+        match received {
+            Some(Some(queued)) => {
+                // Take whatever else is already queued without waiting
+                let mut next = Some(queued);
+                while let Some(queued) = next.take() {
+                    match queued {
+                        Queued::Log(log_message) => writer.push(log_message),
+                        Queued::Evict(hash) => writer.push_eviction(hash),
+                    }
+                    if writer.pending.len() < writer.batch_size {
+                        next = log_receiver.try_recv().ok();
+                    }
+                }
+            }
+            Some(None) => break,
+            None => {}
+        }
+
+        if writer.should_write() {
+            writer.retry_at = None;
+            writer.flush().await;
+        } else if writer.retry_at.is_some() {
+            writer.limit_buffer();
+        }
+    }
+
+    writer.flush_remaining().await;
+}
+
+struct Writer {
+    pending: VecDeque<LogMessage>,
+    /// Position of the first pending record among every record received
+    front: u64,
+    /// When the oldest pending record arrived
+    oldest: Option<Instant>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_buffered: usize,
+    /// Hashes to remove, each with the position of the first record received after it. The
+    /// records before that position are stored first and the ones from it on only afterwards,
+    /// so a failed write can't mix records from either side of an eviction.
+    evictions: VecDeque<(u64, String)>,
+    archive: bool,
+    db_pool: SqlitePool,
+    health: Arc<Mutex<WriterHealth>>,
+    /// Rows per hash and level for the retention sweep
+    counts: Arc<Mutex<LogCounts>>,
+    rejected_sender: mpsc::Sender<RejectedLine>,
+}
+
+impl Writer {
+    fn push(&mut self, log_message: LogMessage) {
+        if self.pending.is_empty() {
+            self.oldest = Some(Instant::now());
+        }
+        self.pending.push_back(log_message);
+    }
+
+    fn push_eviction(&mut self, hash: String) {
+        let position = self.front + self.pending.len() as u64;
+        self.evictions.push_back((position, hash));
+    }
+
+    /// When pending records are to be written at the latest: at a scheduled retry, or a flush
+    /// interval after the oldest arrived.
+    fn write_due(&self) -> Option<Instant> {
+        self.retry_at
+            .or_else(|| self.oldest.map(|oldest| oldest + self.flush_interval))
+    }
+
+    fn should_write(&self) -> bool {
+        let due = self.write_due().is_some_and(|due| Instant::now() >= due);
+        due || (self.retry_at.is_none() && (self.pending.len() >= self.batch_size || !self.evictions.is_empty()))
+    }
+
+    /// Write pending records a batch at a time, and run each eviction once the records before it
+    /// are stored, until everything is done or writing fails.
+    async fn flush(&mut self) {
+        loop {
+            while let Some((position, hash)) = self.evictions.front() {
+                if *position > self.front {
+                    break;
+                }
+                match evict_hash(hash, self.archive, &self.db_pool).await {
+                    Ok(rows) => {
+                        let (_, hash) = self.evictions.pop_front().expect("eviction was just seen");
+                        self.counts.lock().unwrap().remove_hash(&hash);
+                        let action = if self.archive { "Archived" } else { "Deleted" };
+                        println!("{} {} log(s) of evicted hash {}", action, rows, hash);
+                    }
+                    Err(e) => {
+                        self.schedule_retry(&e);
+                        self.limit_buffer();
+                        return;
+                    }
+                }
+            }
+            if self.pending.is_empty() {
+                break;
+            }
+
+            // A batch never reaches past the next eviction
+            let before_eviction = self
+                .evictions
+                .front()
+                .map_or(usize::MAX, |(position, _)| (position - self.front) as usize);
+            let count = self.pending.len().min(self.batch_size).min(before_eviction);
+            let batch = &self.pending.make_contiguous()[..count];
+
+            let result = match write_logs_to_database(batch, &self.db_pool).await {
+                Err(e) if classify(&e) == Failure::BadRows => self.isolate_bad_rows(count).await,
+                result => result,
+            };
+            match result {
+                Ok(()) => {
+                    self.counts.lock().unwrap().add(self.pending.range(..count));
+                    self.pending.drain(..count);
+                    self.front += count as u64;
+                    if self.pending.is_empty() {
+                        self.oldest = None;
+                    }
+                    self.backoff = INITIAL_BACKOFF;
+                    let mut health = self.health.lock().unwrap();
+                    health.written += count as u64;
+                    health.status = "ok";
+                    health.consecutive_failures = 0;
+                    health.last_write_at = Some(chrono::Utc::now().to_rfc3339());
+                }
+                Err(e) => {
+                    self.schedule_retry(&e);
+                    break;
+                }
+            }
+        }
+        self.limit_buffer();
+    }
+
+    /// Write the first `count` pending records one by one, moving the ones the database
+    /// refuses to the rejected lines table. Transient and disk errors are passed up.
+    async fn isolate_bad_rows(&mut self, count: usize) -> Result<(), sqlx::Error> {
+        let mut transaction = self.db_pool.begin().await?;
+        let mut bad_rows = Vec::new();
+
+        for log in self.pending.iter().take(count) {
+            // A failed statement only rolls back itself, not the transaction
+            if let Err(e) = insert_log(log).execute(&mut *transaction).await {
+                if classify(&e) != Failure::BadRows {
+                    return Err(e);
+                }
+                bad_rows.push((log, e));
+            }
+        }
+        transaction.commit().await?;
+
+        if !bad_rows.is_empty() {
+            eprintln!("Moved {} log(s) the database refused to the rejected lines", bad_rows.len());
+            self.health.lock().unwrap().isolated += bad_rows.len() as u64;
+        }
+        for (log, e) in bad_rows {
+            // `flush` counts the whole batch as stored
+            self.counts.lock().unwrap().subtract(&log.hash, &Some(log.level.clone()), 1);
+            let rejected_line = RejectedLine {
+                line: serde_json::to_string(log).unwrap_or_default(),
+                error: e.to_string(),
+                peer: log.peer.clone().unwrap_or_default(),
+                source: "database".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+            };
+            let _ = self.rejected_sender.try_send(rejected_line);
+        }
+        Ok(())
+    }
+
+    fn schedule_retry(&mut self, error: &sqlx::Error) {
+        let failure = classify(error);
+        let delay = match failure {
+            Failure::Unavailable => UNAVAILABLE_RETRY,
+            _ => self.backoff,
+        };
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.retry_at = Some(Instant::now() + delay);
+
+        let mut health = self.health.lock().unwrap();
+        health.retries += 1;
+        health.consecutive_failures += 1;
+        health.status = if failure == Failure::Unavailable || health.consecutive_failures >= DEGRADED_AFTER {
+            "degraded"
+        } else {
+            "retrying"
+        };
+        health.last_error = Some(error.to_string());
+        health.last_error_at = Some(chrono::Utc::now().to_rfc3339());
+        eprintln!(
+            "Failed to write to the database ({} log(s) waiting), retrying in {:?}: {}",
+            self.pending.len(),
+            delay,
+            error
+        );
+    }
+
+    /// Drop the oldest records beyond `max_buffered`.
+    fn limit_buffer(&mut self) {
+        let excess = self.pending.len().saturating_sub(self.max_buffered);
+        self.pending.drain(..excess);
+        self.front += excess as u64;
+
+        let mut health = self.health.lock().unwrap();
+        health.buffered = self.pending.len();
+        health.dropped += excess as u64;
+    }
+
+    /// Last attempts at shutdown, so a short hiccup doesn't lose the final batch.
+    async fn flush_remaining(&mut self) {
+        for _ in 0..DEGRADED_AFTER {
+            self.flush().await;
+            let Some(retry_at) = self.retry_at.take() else { return };
+            sleep_until(retry_at.min(Instant::now() + Duration::from_secs(1))).await;
+        }
+        if !self.pending.is_empty() {
+            eprintln!("Lost {} log(s) that could not be written before shutdown", self.pending.len());
+        }
+    }
+}
+
+fn insert_log(log: &LogMessage) -> sqlx::query::Query<'_, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'_>> {
+    sqlx::query(SINGLE_INSERT.as_str())
+    .bind(&log.level)
+    .bind(log.severity)
+    .bind(&log.message)
+    .bind(&log.target)
+    .bind(&log.module_path)
+    .bind(&log.file)
+    .bind(log.line)
+    .bind(&log.hash)
+    .bind(&log.timestamp)
+    .bind(&log.fields)
+    .bind(&log.peer)
+    .bind(&log.session_id)
+}
+
+/// Delete every row of `hash`, first copying them to `archived_logs` when archiving.
+async fn evict_hash(hash: &str, archive: bool, db_pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    if archive {
+        sqlx::query(&format!(
+            "INSERT INTO archived_logs ({0}, archived_at) SELECT {0}, ? FROM logs WHERE hash = ?",
+            INSERT_COLUMNS
+        ))
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(hash)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    let deleted = sqlx::query("DELETE FROM logs WHERE hash = ?")
+        .bind(hash)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    transaction.commit().await?;
+    Ok(deleted)
+}
+
+static SINGLE_INSERT: Lazy<String> =
+    Lazy::new(|| format!("INSERT INTO logs ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", INSERT_COLUMNS));
+
+/// Write `logs` in one transaction, up to `ROWS_PER_STATEMENT` rows per statement. Full-size
+/// statements all share the same SQL, so SQLite reuses the prepared statement.
+async fn write_logs_to_database(logs: &[LogMessage], db_pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    for chunk in logs.chunks(ROWS_PER_STATEMENT) {
+        let mut builder = QueryBuilder::<Sqlite>::new(format!("INSERT INTO logs ({}) ", INSERT_COLUMNS));
+        builder.push_values(chunk, |mut row, log| {
+            row.push_bind(&log.level)
+                .push_bind(log.severity)
+                .push_bind(&log.message)
+                .push_bind(&log.target)
+                .push_bind(&log.module_path)
+                .push_bind(&log.file)
+                .push_bind(log.line)
+                .push_bind(&log.hash)
+                .push_bind(&log.timestamp)
+                .push_bind(&log.fields)
+                .push_bind(&log.peer)
+                .push_bind(&log.session_id);
+        });
+        builder.build().execute(&mut *transaction).await?;
+    }
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    /// Stands in for an SQLite error with the given result code.
+    #[derive(Debug)]
+    struct CodedError(&'static str);
+
+    impl std::fmt::Display for CodedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodedError {}
+
+    impl sqlx::error::DatabaseError for CodedError {
+        fn message(&self) -> &str {
+            "coded error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn coded(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(CodedError(code)))
+    }
+
+    fn log(hash: &str, message: &str) -> LogMessage {
+        LogMessage {
+            level: "INFO".to_string(),
+            severity: Some(6),
+            message: message.to_string(),
+            target: "test".to_string(),
+            module_path: None,
+            file: None,
+            line: None,
+            hash: hash.to_string(),
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            fields: None,
+            peer: None,
+            session_id: None,
+        }
+    }
+
+    async fn memory_pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        crate::schema::migrate(&pool, &[]).await;
+        pool
+    }
+
+    fn new_writer(db_pool: SqlitePool, batch_size: usize, max_buffered: usize) -> (Writer, mpsc::Receiver<RejectedLine>) {
+        let (rejected_sender, rejected_receiver) = mpsc::channel(100);
+        let writer = Writer {
+            pending: VecDeque::new(),
+            front: 0,
+            oldest: None,
+            retry_at: None,
+            backoff: INITIAL_BACKOFF,
+            batch_size,
+            flush_interval: Duration::from_secs(60),
+            max_buffered,
+            evictions: VecDeque::new(),
+            archive: false,
+            db_pool,
+            health: Arc::new(Mutex::new(WriterHealth::new())),
+            counts: Arc::new(Mutex::new(LogCounts::new())),
+            rejected_sender,
+        };
+        (writer, rejected_receiver)
+    }
+
+    async fn stored_messages(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query_scalar("SELECT message FROM logs ORDER BY id").fetch_all(pool).await.unwrap()
+    }
+
+    fn pending_messages(writer: &Writer) -> Vec<&str> {
+        writer.pending.iter().map(|log| log.message.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn classifies_sqlite_errors() {
+        assert_eq!(classify(&coded("5")), Failure::Transient);
+        // SQLITE_BUSY_SNAPSHOT and SQLITE_LOCKED_SHAREDCACHE are extended codes of the same
+        assert_eq!(classify(&coded("517")), Failure::Transient);
+        assert_eq!(classify(&coded("262")), Failure::Transient);
+        assert_eq!(classify(&sqlx::Error::PoolTimedOut), Failure::Transient);
+
+        assert_eq!(classify(&coded("1555")), Failure::BadRows);
+        assert_eq!(classify(&coded("18")), Failure::BadRows);
+        assert_eq!(classify(&coded("20")), Failure::BadRows);
+        assert_eq!(classify(&coded("25")), Failure::BadRows);
+
+        // SQLITE_FULL, SQLITE_IOERR_WRITE, SQLITE_READONLY
+        assert_eq!(classify(&coded("13")), Failure::Unavailable);
+        assert_eq!(classify(&coded("778")), Failure::Unavailable);
+        assert_eq!(classify(&coded("8")), Failure::Unavailable);
+        assert_eq!(classify(&sqlx::Error::PoolClosed), Failure::Unavailable);
+
+        // And the codes SQLite really reports
+        let pool = memory_pool().await;
+        let duplicate = "INSERT INTO logs (id, level) VALUES (1, 'INFO')";
+        sqlx::query(duplicate).execute(&pool).await.unwrap();
+        let error = sqlx::query(duplicate).execute(&pool).await.unwrap_err();
+        assert_eq!(classify(&error), Failure::BadRows);
+        sqlx::query("PRAGMA query_only = ON").execute(&pool).await.unwrap();
+        let error = write_logs_to_database(&[log("a", "one")], &pool).await.unwrap_err();
+        assert_eq!(classify(&error), Failure::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn backs_off_and_reports_degraded_after_repeated_failures() {
+        let (mut writer, _rejected) = new_writer(memory_pool().await, 10, 100);
+        let mut delays = Vec::new();
+        for _ in 0..DEGRADED_AFTER {
+            let before = Instant::now();
+            assert_ne!(writer.health.lock().unwrap().status, "degraded");
+            writer.schedule_retry(&coded("5"));
+            delays.push(writer.retry_at.unwrap().duration_since(before).as_millis() / 100);
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 16]);
+        let health = writer.health.lock().unwrap().clone();
+        assert_eq!(health.status, "degraded");
+        assert_eq!(health.retries, DEGRADED_AFTER);
+
+        // A database that can't be written at all is degraded straight away
+        let (mut writer, _rejected) = new_writer(memory_pool().await, 10, 100);
+        writer.schedule_retry(&coded("13"));
+        assert!(writer.health.lock().unwrap().is_degraded());
+        assert!(writer.retry_at.unwrap() >= Instant::now() + UNAVAILABLE_RETRY - Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_records_beyond_the_buffer() {
+        let (mut writer, _rejected) = new_writer(memory_pool().await, 10, 3);
+        for message in ["1", "2", "3", "4", "5"] {
+            writer.push(log("a", message));
+        }
+        writer.limit_buffer();
+        assert_eq!(pending_messages(&writer), ["3", "4", "5"]);
+        assert_eq!(writer.front, 2);
+        let health = writer.health.lock().unwrap().clone();
+        assert_eq!((health.buffered, health.dropped), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn holds_records_until_the_database_can_be_written() {
+        let pool = memory_pool().await;
+        let (mut writer, _rejected) = new_writer(pool.clone(), 10, 100);
+        sqlx::query("PRAGMA query_only = ON").execute(&pool).await.unwrap();
+        writer.push(log("a", "one"));
+        writer.push(log("a", "two"));
+        writer.flush().await;
+        assert_eq!(pending_messages(&writer), ["one", "two"]);
+        assert!(writer.retry_at.is_some());
+        assert!(writer.health.lock().unwrap().is_degraded());
+
+        sqlx::query("PRAGMA query_only = OFF").execute(&pool).await.unwrap();
+        writer.retry_at = None;
+        writer.flush().await;
+        assert!(writer.pending.is_empty());
+        assert_eq!(stored_messages(&pool).await, ["one", "two"]);
+        let health = writer.health.lock().unwrap().clone();
+        assert_eq!((health.status, health.written, health.buffered), ("ok", 2, 0));
+    }
+
+    #[tokio::test]
+    async fn moves_rows_the_database_refuses_to_the_rejected_lines() {
+        let pool = memory_pool().await;
+        sqlx::query("CREATE TRIGGER refuse BEFORE INSERT ON logs WHEN NEW.message = 'bad' BEGIN SELECT RAISE(ABORT, 'refused'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (mut writer, mut rejected) = new_writer(pool.clone(), 10, 100);
+        for message in ["one", "bad", "two"] {
+            writer.push(log("a", message));
+        }
+        writer.flush().await;
+
+        assert!(writer.pending.is_empty());
+        assert_eq!(stored_messages(&pool).await, ["one", "two"]);
+        let rejected_line = rejected.try_recv().unwrap();
+        assert_eq!(rejected_line.source, "database");
+        assert!(rejected_line.line.contains("\"bad\""));
+        assert!(rejected_line.error.contains("refused"));
+        let health = writer.health.lock().unwrap().clone();
+        assert_eq!((health.written, health.isolated), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn writes_batches_larger_than_one_statement_in_order() {
+        let pool = memory_pool().await;
+        let count = 2 * ROWS_PER_STATEMENT + 88;
+        let logs: Vec<LogMessage> = (0..count).map(|n| log("a", &n.to_string())).collect();
+        write_logs_to_database(&logs, &pool).await.unwrap();
+
+        let expected: Vec<String> = (0..count).map(|n| n.to_string()).collect();
+        assert_eq!(stored_messages(&pool).await, expected);
+    }
+
+    #[tokio::test]
+    async fn rolls_back_earlier_statements_when_a_later_one_fails() {
+        let pool = memory_pool().await;
+        sqlx::query("CREATE TRIGGER refuse BEFORE INSERT ON logs WHEN NEW.message = 'bad' BEGIN SELECT RAISE(ABORT, 'refused'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut logs: Vec<LogMessage> = (0..ROWS_PER_STATEMENT + 1).map(|n| log("a", &n.to_string())).collect();
+        logs.push(log("a", "bad"));
+        assert!(write_logs_to_database(&logs, &pool).await.is_err());
+        assert!(stored_messages(&pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn writes_once_a_batch_is_full_or_has_waited_long_enough() {
+        let pool = memory_pool().await;
+        let (mut writer, _rejected) = new_writer(pool.clone(), 3, 100);
+        assert_eq!(writer.write_due(), None);
+        writer.push(log("a", "one"));
+        writer.push(log("a", "two"));
+        assert!(!writer.should_write());
+        assert!(writer.write_due().is_some_and(|due| due > Instant::now()));
+        writer.push(log("a", "three"));
+        assert!(writer.should_write());
+
+        writer.flush_interval = Duration::ZERO;
+        writer.push(log("a", "four"));
+        writer.flush().await;
+        assert_eq!(stored_messages(&pool).await, ["one", "two", "three", "four"]);
+        assert_eq!(writer.write_due(), None);
+
+        writer.push(log("a", "five"));
+        assert!(writer.should_write(), "an interval of zero writes straight away");
+    }
+
+    #[tokio::test]
+    async fn evicts_only_the_records_queued_before_the_eviction() {
+        let pool = memory_pool().await;
+        let (mut writer, _rejected) = new_writer(pool.clone(), 10, 100);
+        writer.push(log("a", "old"));
+        writer.push(log("b", "other"));
+        writer.push_eviction("a".to_string());
+        writer.push(log("a", "new"));
+
+        // Nothing is written while the database is unavailable, and the order still holds after
+        sqlx::query("PRAGMA query_only = ON").execute(&pool).await.unwrap();
+        writer.flush().await;
+        assert_eq!(writer.evictions.len(), 1);
+        sqlx::query("PRAGMA query_only = OFF").execute(&pool).await.unwrap();
+        writer.retry_at = None;
+        writer.flush().await;
+
+        assert!(writer.evictions.is_empty());
+        assert_eq!(stored_messages(&pool).await, ["other", "new"]);
+    }
+
+    #[tokio::test]
+    async fn archives_evicted_records() {
+        let pool = memory_pool().await;
+        let (mut writer, _rejected) = new_writer(pool.clone(), 10, 100);
+        writer.archive = true;
+        writer.push(log("a", "old"));
+        writer.push_eviction("a".to_string());
+        writer.flush().await;
+
+        assert!(stored_messages(&pool).await.is_empty());
+        let archived: Vec<String> = sqlx::query_scalar("SELECT message FROM archived_logs").fetch_all(&pool).await.unwrap();
+        assert_eq!(archived, ["old"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_an_eviction_in_place_when_older_records_are_dropped() {
+        let pool = memory_pool().await;
+        let (mut writer, _rejected) = new_writer(pool.clone(), 10, 1);
+        writer.push(log("a", "old"));
+        writer.push(log("a", "older"));
+        writer.push_eviction("a".to_string());
+        writer.push(log("a", "new"));
+        writer.limit_buffer();
+        writer.flush().await;
+
+        assert_eq!(stored_messages(&pool).await, ["new"]);
+    }
+}